@@ -0,0 +1,226 @@
+use core::{mem, slice};
+
+use spin::Mutex;
+use x86_64::PhysAddr;
+
+use crate::memory::phys_to_virt;
+
+pub use self::madt::{Madt, MadtEntry};
+pub use self::fadt::{Fadt, GenericAddress};
+pub use self::hpet::Hpet;
+pub use self::mcfg::{Mcfg, McfgEntry};
+
+pub mod madt;
+pub mod fadt;
+pub mod hpet;
+pub mod mcfg;
+
+// RSDP is 16 byte aligned in first KiB of EBDA or in BIOS read-only
+// area, segment of EBDA is stored in BIOS data area
+const EBDA_SEGMENT_PTR: u64 = 0x40e;
+const BIOS_AREA: (u64, u64) = (0xe_0000, 0x10_0000);
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const RSDP_V1_LENGTH: usize = 20;
+
+// root table found by `init`, all other tables are looked up through it
+static ROOT: Mutex<Option<RootTable>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    RsdpNotFound,
+    InvalidChecksum([u8; 4]),  // signature of the table
+    InvalidSignature([u8; 4]),
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct Rsdp {
+    pub signature: [u8; 8],
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub revision: u8,  // 0 for ACPI 1.0, rest of the fields are valid if 2 or above
+    pub rsdt_address: u32,
+    pub length: u32,
+    pub xsdt_address: u64,
+    pub extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+// common header of all system description tables
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,  // including header
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+impl SdtHeader {
+    // whole table including header, unsafe as header must be followed
+    // by `length` bytes of table
+    pub unsafe fn bytes(&self) -> &[u8] {
+        slice::from_raw_parts(self as *const _ as *const u8, self.length as usize)
+    }
+
+    // table data after header
+    pub unsafe fn data(&self) -> &[u8] {
+        &self.bytes()[mem::size_of::<SdtHeader>()..]
+    }
+}
+
+// RSDT lists 32 bit table addresses, XSDT (ACPI 2.0+) 64 bit ones
+#[derive(Debug, Clone, Copy)]
+struct RootTable {
+    header: PhysAddr,
+    entry_size: usize,
+}
+
+impl RootTable {
+    fn tables(&self) -> impl Iterator<Item = PhysAddr> {
+        let header = unsafe { table_header(self.header) };
+        let data = unsafe { header.data() };
+        let entry_size = self.entry_size;
+        (0..data.len() / entry_size).map(move |i| {
+            let entry = &data[i * entry_size..(i + 1) * entry_size];
+            let addr = entry.iter().rev().fold(0, |addr, &byte| addr << 8 | u64::from(byte));  // little endian
+            PhysAddr::new(addr)
+        })
+    }
+}
+
+// find RSDP and validate root table, must be called after `memory::init`
+// as tables are accessed through physical memory mapping
+pub fn init() -> Result<Rsdp, AcpiError> {
+    let rsdp = find_rsdp().ok_or(AcpiError::RsdpNotFound)?;
+    let root = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        RootTable { header: PhysAddr::new(rsdp.xsdt_address), entry_size: 8 }
+    } else {
+        RootTable { header: PhysAddr::new(u64::from(rsdp.rsdt_address)), entry_size: 4 }
+    };
+
+    let header = unsafe { table_header(root.header) };
+    let expected = if root.entry_size == 8 { b"XSDT" } else { b"RSDT" };
+    if &header.signature != expected {
+        return Err(AcpiError::InvalidSignature(header.signature));
+    }
+    if !checksum_valid(unsafe { header.bytes() }) {
+        return Err(AcpiError::InvalidChecksum(header.signature));
+    }
+
+    *ROOT.lock() = Some(root);
+    Ok(rsdp)
+}
+
+// first table with given signature whose checksum is valid, none if
+// `init` wasn't successful
+pub fn find_table(signature: [u8; 4]) -> Option<&'static SdtHeader> {
+    let root = (*ROOT.lock())?;
+    root.tables()
+        .map(|addr| unsafe { table_header(addr) })
+        .find(|header| header.signature == signature && checksum_valid(unsafe { header.bytes() }))
+}
+
+pub fn madt() -> Option<Madt> {
+    find_table(*b"APIC").map(|header| unsafe { Madt::new(header) })
+}
+
+pub fn fadt() -> Option<Fadt> {
+    find_table(*b"FACP").map(|header| unsafe { Fadt::new(header) })
+}
+
+pub fn hpet() -> Option<Hpet> {
+    find_table(*b"HPET").map(|header| unsafe { Hpet::new(header) })
+}
+
+pub fn mcfg() -> Option<Mcfg> {
+    find_table(*b"MCFG").map(|header| unsafe { Mcfg::new(header) })
+}
+
+// differentiated system description table, it's pointed to by FADT
+// instead of root table; contains AML code, not parsed here
+pub fn dsdt() -> Option<&'static SdtHeader> {
+    let addr = fadt()?.dsdt();
+    if addr.as_u64() == 0 {
+        return None;
+    }
+    let header = unsafe { table_header(addr) };
+    if &header.signature == b"DSDT" && checksum_valid(unsafe { header.bytes() }) {
+        Some(header)
+    } else {
+        None
+    }
+}
+
+// sum of all bytes of a valid table is zero
+pub fn checksum_valid(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+fn find_rsdp() -> Option<Rsdp> {
+    let ebda = u64::from(unsafe { *phys_to_virt(PhysAddr::new(EBDA_SEGMENT_PTR)).as_ptr::<u16>() }) << 4;
+    let areas = [(ebda, ebda + 1024), BIOS_AREA];
+    areas.iter()
+        .filter(|&&(start, _)| start != 0)
+        .flat_map(|&(start, end)| (start..end).step_by(16))
+        .filter_map(|addr| unsafe { rsdp_at(PhysAddr::new(addr)) })
+        .next()
+}
+
+// RSDP at given address if signature and checksum match, checksum of
+// first 20 bytes is always checked, extended one only for ACPI 2.0+
+unsafe fn rsdp_at(addr: PhysAddr) -> Option<Rsdp> {
+    let ptr: *const u8 = phys_to_virt(addr).as_ptr();
+    if slice::from_raw_parts(ptr, RSDP_SIGNATURE.len()) != RSDP_SIGNATURE {
+        return None;
+    }
+    if !checksum_valid(slice::from_raw_parts(ptr, RSDP_V1_LENGTH)) {
+        return None;
+    }
+    let rsdp = *(ptr as *const Rsdp);
+    if rsdp.revision >= 2 && !checksum_valid(slice::from_raw_parts(ptr, rsdp.length as usize)) {
+        return None;
+    }
+    Some(rsdp)
+}
+
+unsafe fn table_header(addr: PhysAddr) -> &'static SdtHeader {
+    &*phys_to_virt(addr).as_ptr()
+}
+
+// read a little endian value of type `T` at given offset of a table
+fn read<T: Copy>(bytes: &[u8], offset: usize) -> T {
+    assert!(offset + mem::size_of::<T>() <= bytes.len(), "read beyond end of table");
+    unsafe { (bytes.as_ptr().add(offset) as *const T).read_unaligned() }
+}
+
+
+#[cfg(all(test, not(target_os = "none")))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn validates_checksum() {
+        assert!(checksum_valid(&[0x10, 0xf0]));
+        assert!(checksum_valid(&[]));
+        assert!(!checksum_valid(&[0x10, 0xef]));
+    }
+
+    #[test]
+    fn reads_unaligned_values() {
+        let bytes = [0xff, 0x78, 0x56, 0x34, 0x12];
+        assert_eq!(read::<u32>(&bytes, 1), 0x1234_5678);
+        assert_eq!(read::<u8>(&bytes, 0), 0xff);
+    }
+
+    #[test]
+    fn header_has_spec_size() {
+        assert_eq!(mem::size_of::<SdtHeader>(), 36);
+        assert_eq!(mem::size_of::<Rsdp>(), 36);
+    }
+}
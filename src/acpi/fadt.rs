@@ -0,0 +1,250 @@
+use core::ptr;
+
+use spin::Mutex;
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::instructions::port::Port;
+
+use crate::memory::{map_mmio, MappingError};
+use super::{read, SdtHeader};
+
+// flag telling reset register is supported
+const RESET_REG_SUP: u32 = 1 << 10;
+
+// memory mapped registers which were mapped already by physical
+// address, enough for all registers FADT describes
+const MAX_MAPPED: usize = 8;
+static MAPPED: Mutex<[Option<(PhysAddr, VirtAddr)>; MAX_MAPPED]> = Mutex::new([None; MAX_MAPPED]);
+
+// Fixed ACPI Description Table (signature "FACP"), fields are read by
+// offset since table length differs across ACPI versions and fields
+// beyond table length don't exist
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt {
+    bytes: &'static [u8],  // whole table including header
+}
+
+// describes location of a register, used by ACPI 2.0+ tables
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,  // 1 byte, 2 word, 3 dword, 4 qword, 0 undefined
+    pub address: u64,
+}
+
+impl GenericAddress {
+    pub const SYSTEM_MEMORY: u8 = 0;
+    pub const SYSTEM_IO: u8 = 1;
+    pub const PCI_CONFIG: u8 = 2;
+
+    // panics if `bytes` doesn't contain 12 bytes at `offset`
+    pub(super) fn parse(bytes: &[u8], offset: usize) -> Self {
+        GenericAddress {
+            address_space: read(bytes, offset),
+            bit_width: read(bytes, offset + 1),
+            bit_offset: read(bytes, offset + 2),
+            access_size: read(bytes, offset + 3),
+            address: read(bytes, offset + 4),
+        }
+    }
+
+    // I/O port, if register is in I/O space
+    pub fn io_port(&self) -> Option<u16> {
+        if self.address_space == Self::SYSTEM_IO && self.address != 0 && self.address <= 0xffff {
+            Some(self.address as u16)
+        } else {
+            None
+        }
+    }
+
+    // read and write register in I/O or memory space with its width,
+    // memory mapped registers are mapped on first access
+    pub unsafe fn read(&self) -> Result<u32, MappingError> {
+        let value = match self.address_space {
+            Self::SYSTEM_IO => {
+                let port = self.address as u16;
+                match self.width() {
+                    1 => u32::from(Port::<u8>::new(port).read()),
+                    2 => u32::from(Port::<u16>::new(port).read()),
+                    _ => Port::<u32>::new(port).read(),
+                }
+            },
+            Self::SYSTEM_MEMORY => {
+                let addr = self.mapped()?;
+                match self.width() {
+                    1 => u32::from(ptr::read_volatile(addr.as_ptr::<u8>())),
+                    2 => u32::from(ptr::read_volatile(addr.as_ptr::<u16>())),
+                    _ => ptr::read_volatile(addr.as_ptr::<u32>()),
+                }
+            },
+            _ => 0,
+        };
+        Ok(value)
+    }
+
+    pub unsafe fn write(&self, value: u32) -> Result<(), MappingError> {
+        match self.address_space {
+            Self::SYSTEM_IO => {
+                let port = self.address as u16;
+                match self.width() {
+                    1 => Port::<u8>::new(port).write(value as u8),
+                    2 => Port::<u16>::new(port).write(value as u16),
+                    _ => Port::<u32>::new(port).write(value),
+                }
+            },
+            Self::SYSTEM_MEMORY => {
+                let addr = self.mapped()?;
+                match self.width() {
+                    1 => ptr::write_volatile(addr.as_mut_ptr::<u8>(), value as u8),
+                    2 => ptr::write_volatile(addr.as_mut_ptr::<u16>(), value as u16),
+                    _ => ptr::write_volatile(addr.as_mut_ptr::<u32>(), value),
+                }
+            },
+            _ => {},  // PCI configuration space and others are not supported
+        }
+        Ok(())
+    }
+
+    // virtual address of memory mapped register, mapping is reused by
+    // all registers at the same address; if table is full, register is
+    // mapped every time
+    fn mapped(&self) -> Result<VirtAddr, MappingError> {
+        let addr = PhysAddr::new(self.address);
+        let mut mapped = MAPPED.lock();
+        if let Some((_, virt)) = mapped.iter().flatten().find(|(phys, _)| *phys == addr) {
+            return Ok(*virt);
+        }
+        let virt = map_mmio(addr, 8)?;
+        if let Some(slot) = mapped.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some((addr, virt));
+        }
+        Ok(virt)
+    }
+
+    // access width in bytes, from access size if defined or bit width
+    fn width(&self) -> u8 {
+        match self.access_size {
+            1..=4 => 1 << (self.access_size - 1),
+            _ => (self.bit_width / 8).max(1),
+        }
+    }
+}
+
+impl Fadt {
+    // unsafe as header must be followed by a valid FADT
+    pub unsafe fn new(header: &'static SdtHeader) -> Self {
+        Fadt::from_bytes(header.bytes())
+    }
+
+    fn from_bytes(bytes: &'static [u8]) -> Self {
+        Fadt { bytes }
+    }
+
+    // 64 bit address is used if present
+    pub fn dsdt(&self) -> PhysAddr {
+        let x_dsdt = self.field::<u64>(140).unwrap_or(0);
+        if x_dsdt != 0 {
+            PhysAddr::new(x_dsdt)
+        } else {
+            PhysAddr::new(u64::from(self.field::<u32>(40).unwrap_or(0)))
+        }
+    }
+
+    pub fn sci_interrupt(&self) -> Option<u16> {
+        self.field(46)
+    }
+
+    // port to write `acpi_enable` value to, zero if ACPI mode is always on
+    pub fn smi_command_port(&self) -> Option<u32> {
+        self.field(48)
+    }
+
+    pub fn acpi_enable(&self) -> Option<u8> {
+        self.field(52)
+    }
+
+    // PM1 control register blocks, b is optional
+    pub fn pm1a_control_block(&self) -> Option<GenericAddress> {
+        self.io_block(172, 64, 89)
+    }
+
+    pub fn pm1b_control_block(&self) -> Option<GenericAddress> {
+        self.io_block(184, 68, 89)
+    }
+
+    pub fn pm_timer_block(&self) -> Option<GenericAddress> {
+        self.io_block(208, 76, 91)
+    }
+
+    // RTC CMOS index of century, zero if not supported
+    pub fn century(&self) -> Option<u8> {
+        self.field(108).filter(|&century| century != 0)
+    }
+
+    pub fn flags(&self) -> u32 {
+        self.field(112).unwrap_or(0)
+    }
+
+    // reset register and value to write to it to reset the system, if
+    // supported
+    pub fn reset_register(&self) -> Option<(GenericAddress, u8)> {
+        if self.flags() & RESET_REG_SUP == 0 || self.bytes.len() < 129 {
+            return None;
+        }
+        Some((GenericAddress::parse(self.bytes, 116), self.field(128)?))
+    }
+
+    // extended (generic address) block if present, otherwise I/O port
+    // block of 1.0 table with length given at `length_offset`
+    fn io_block(&self, x_offset: usize, offset: usize, length_offset: usize) -> Option<GenericAddress> {
+        if self.bytes.len() >= x_offset + 12 {
+            let block = GenericAddress::parse(self.bytes, x_offset);
+            if block.address != 0 {
+                return Some(block);
+            }
+        }
+        let port = self.field::<u32>(offset)?;
+        if port == 0 {
+            return None;
+        }
+        Some(GenericAddress {
+            address_space: GenericAddress::SYSTEM_IO,
+            bit_width: self.field::<u8>(length_offset)? * 8,
+            bit_offset: 0,
+            access_size: 0,
+            address: u64::from(port),
+        })
+    }
+
+    fn field<T: Copy>(&self, offset: usize) -> Option<T> {
+        if offset + core::mem::size_of::<T>() <= self.bytes.len() {
+            Some(read(self.bytes, offset))
+        } else {
+            None
+        }
+    }
+}
+
+
+#[cfg(all(test, not(target_os = "none")))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reads_acpi_1_table() {
+        let bytes: &'static mut [u8; 116] = Box::leak(Box::new([0; 116]));
+        bytes[40] = 0x40;  // DSDT
+        bytes[64] = 0x04;  // PM1a control port 0x604
+        bytes[65] = 0x06;
+        bytes[89] = 2;
+        bytes[108] = 0x32;
+        let fadt = Fadt::from_bytes(bytes);
+        assert_eq!(fadt.dsdt(), PhysAddr::new(0x40));
+        assert_eq!(fadt.pm1a_control_block().and_then(|block| block.io_port()), Some(0x604));
+        assert_eq!(fadt.pm1a_control_block().unwrap().bit_width, 16);
+        assert_eq!(fadt.pm1b_control_block(), None);
+        assert_eq!(fadt.century(), Some(0x32));
+        assert_eq!(fadt.reset_register(), None);  // table is too short
+    }
+}
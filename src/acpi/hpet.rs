@@ -0,0 +1,41 @@
+use x86_64::PhysAddr;
+
+use super::{read, SdtHeader, GenericAddress};
+
+// High Precision Event Timer description table (signature "HPET")
+#[derive(Debug, Clone, Copy)]
+pub struct Hpet {
+    bytes: &'static [u8],  // whole table including header
+}
+
+impl Hpet {
+    // unsafe as header must be followed by a valid HPET table
+    pub unsafe fn new(header: &'static SdtHeader) -> Self {
+        Hpet { bytes: header.bytes() }
+    }
+
+    // hardware revision, comparator count, counter size and vendor id,
+    // same as low half of general capabilities register
+    pub fn event_timer_block_id(&self) -> u32 {
+        read(self.bytes, 36)
+    }
+
+    // registers are always memory mapped
+    pub fn base_address(&self) -> PhysAddr {
+        PhysAddr::new(GenericAddress::parse(self.bytes, 40).address)
+    }
+
+    pub fn number(&self) -> u8 {
+        read(self.bytes, 52)
+    }
+
+    // minimum clock ticks periodic mode can be set to without losing
+    // interrupts
+    pub fn minimum_tick(&self) -> u16 {
+        read(self.bytes, 53)
+    }
+
+    pub fn comparator_count(&self) -> u8 {
+        ((self.event_timer_block_id() >> 8) & 0x1f) as u8 + 1
+    }
+}
@@ -0,0 +1,175 @@
+use x86_64::PhysAddr;
+
+use crate::apic::{ApicConfig, IrqRoute};
+use super::{read, SdtHeader};
+
+const PCAT_COMPAT: u32 = 1;  // flag, system also has dual 8259 PICs
+
+// polarity and trigger mode in MPS INTI flags, 0 means bus default
+// which is active high and edge triggered for ISA
+const POLARITY_ACTIVE_LOW: u16 = 0b11;
+const TRIGGER_LEVEL: u16 = 0b11 << 2;
+
+// Multiple APIC Description Table (signature "APIC"), lists interrupt
+// controllers of the system
+#[derive(Debug, Clone, Copy)]
+pub struct Madt {
+    data: &'static [u8],  // table without header
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MadtEntry {
+    LocalApic { processor_id: u8, apic_id: u8, flags: u32 },
+    IoApic { id: u8, address: u32, gsi_base: u32 },
+    InterruptOverride { bus: u8, source: u8, gsi: u32, flags: u16 },  // ISA IRQ `source` is connected to `gsi`
+    NmiSource { flags: u16, gsi: u32 },
+    LocalApicNmi { processor_id: u8, flags: u16, lint: u8 },  // processor id 0xff means all processors
+    LocalApicAddressOverride { address: u64 },
+    Unknown { entry_type: u8 },
+}
+
+impl Madt {
+    // unsafe as header must be followed by a valid MADT
+    pub unsafe fn new(header: &'static SdtHeader) -> Self {
+        Madt::from_data(header.data())
+    }
+
+    fn from_data(data: &'static [u8]) -> Self {
+        Madt { data }
+    }
+
+    pub fn local_apic_address(&self) -> PhysAddr {
+        let address = self.entries()
+            .filter_map(|entry| match entry {
+                MadtEntry::LocalApicAddressOverride { address } => Some(address),
+                _ => None,
+            })
+            .next();
+        PhysAddr::new(address.unwrap_or_else(|| u64::from(read::<u32>(self.data, 0))))
+    }
+
+    pub fn has_legacy_pics(&self) -> bool {
+        read::<u32>(self.data, 4) & PCAT_COMPAT != 0
+    }
+
+    pub fn entries(&self) -> Entries {
+        Entries { data: self.data, offset: 8 }
+    }
+
+    // I/O APIC handling ISA IRQs and how each IRQ is routed to it;
+    // ISA IRQs are identity mapped to GSIs unless overridden
+    pub fn apic_config(&self) -> Option<ApicConfig> {
+        let (io_apic_addr, io_apic_gsi_base) = self.entries()
+            .filter_map(|entry| match entry {
+                MadtEntry::IoApic { address, gsi_base, .. } => Some((address, gsi_base)),
+                _ => None,
+            })
+            .min_by_key(|&(_, gsi_base)| gsi_base)?;  // ISA IRQs start from GSI 0
+
+        let mut isa_routes = [IrqRoute { gsi: 0, active_low: false, level_triggered: false }; 16];
+        for (irq, route) in isa_routes.iter_mut().enumerate() {
+            route.gsi = irq as u32;
+        }
+        for entry in self.entries() {
+            if let MadtEntry::InterruptOverride { source, gsi, flags, .. } = entry {
+                if let Some(route) = isa_routes.get_mut(usize::from(source)) {
+                    route.gsi = gsi;
+                    route.active_low = flags & POLARITY_ACTIVE_LOW == POLARITY_ACTIVE_LOW;
+                    route.level_triggered = flags & TRIGGER_LEVEL == TRIGGER_LEVEL;
+                }
+            }
+        }
+
+        Some(ApicConfig {
+            local_apic_addr: self.local_apic_address(),
+            io_apic_addr: PhysAddr::new(u64::from(io_apic_addr)),
+            io_apic_gsi_base,
+            isa_routes,
+        })
+    }
+}
+
+// variable length entries, each starts with type and length bytes
+pub struct Entries {
+    data: &'static [u8],
+    offset: usize,
+}
+
+impl Iterator for Entries {
+    type Item = MadtEntry;
+
+    fn next(&mut self) -> Option<MadtEntry> {
+        if self.offset + 2 > self.data.len() {
+            return None;
+        }
+        let entry_type = self.data[self.offset];
+        let length = usize::from(self.data[self.offset + 1]);
+        if length < 2 || self.offset + length > self.data.len() {
+            return None;  // malformed, stop here instead of looping forever
+        }
+        let entry = &self.data[self.offset..self.offset + length];
+        self.offset += length;
+
+        Some(match (entry_type, length) {
+            (0, 8) => MadtEntry::LocalApic {
+                processor_id: entry[2],
+                apic_id: entry[3],
+                flags: read(entry, 4),
+            },
+            (1, 12) => MadtEntry::IoApic { id: entry[2], address: read(entry, 4), gsi_base: read(entry, 8) },
+            (2, 10) => MadtEntry::InterruptOverride {
+                bus: entry[2],
+                source: entry[3],
+                gsi: read(entry, 4),
+                flags: read(entry, 8),
+            },
+            (3, 8) => MadtEntry::NmiSource { flags: read(entry, 2), gsi: read(entry, 4) },
+            (4, 6) => MadtEntry::LocalApicNmi { processor_id: entry[2], flags: read(entry, 3), lint: entry[5] },
+            (5, 12) => MadtEntry::LocalApicAddressOverride { address: read(entry, 4) },
+            _ => MadtEntry::Unknown { entry_type },
+        })
+    }
+}
+
+
+#[cfg(all(test, not(target_os = "none")))]
+mod test {
+    use super::*;
+
+    // what QEMU provides with one CPU
+    static MADT: [u8; 48] = [
+        0x00, 0x00, 0xe0, 0xfe, 0x01, 0x00, 0x00, 0x00,  // local APIC address, flags
+        0x00, 0x08, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,  // local APIC
+        0x01, 0x0c, 0x00, 0x00, 0x00, 0x00, 0xc0, 0xfe, 0x00, 0x00, 0x00, 0x00,  // I/O APIC
+        0x02, 0x0a, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00,  // IRQ 0 -> GSI 2
+        0x02, 0x0a, 0x00, 0x09, 0x09, 0x00, 0x00, 0x00, 0x0d, 0x00,  // IRQ 9, active high level
+    ];
+
+    #[test]
+    fn parses_entries() {
+        let madt = Madt::from_data(&MADT);
+        let mut entries = madt.entries();
+        assert_eq!(entries.next(), Some(MadtEntry::LocalApic { processor_id: 0, apic_id: 0, flags: 1 }));
+        assert_eq!(entries.next(), Some(MadtEntry::IoApic { id: 0, address: 0xfec0_0000, gsi_base: 0 }));
+        assert_eq!(entries.next(), Some(MadtEntry::InterruptOverride { bus: 0, source: 0, gsi: 2, flags: 0 }));
+        assert_eq!(entries.next(), Some(MadtEntry::InterruptOverride { bus: 0, source: 9, gsi: 9, flags: 0xd }));
+        assert_eq!(entries.next(), None);
+        assert_eq!(madt.local_apic_address(), PhysAddr::new(0xfee0_0000));
+        assert!(madt.has_legacy_pics());
+    }
+
+    #[test]
+    fn builds_apic_config() {
+        let config = Madt::from_data(&MADT).apic_config().unwrap();
+        assert_eq!(config.io_apic_addr, PhysAddr::new(0xfec0_0000));
+        assert_eq!(config.isa_routes[0].gsi, 2);
+        assert_eq!(config.isa_routes[1], IrqRoute { gsi: 1, active_low: false, level_triggered: false });
+        assert_eq!(config.isa_routes[9], IrqRoute { gsi: 9, active_low: false, level_triggered: true });
+    }
+
+    #[test]
+    fn stops_at_malformed_entry() {
+        static BAD: [u8; 12] = [0, 0, 0, 0, 0, 0, 0, 0, 0x01, 0x00, 0x00, 0x00];
+        assert_eq!(Madt::from_data(&BAD).entries().next(), None);
+    }
+}
@@ -0,0 +1,78 @@
+use x86_64::PhysAddr;
+
+use super::{read, SdtHeader};
+
+const ENTRIES_OFFSET: usize = 8;  // after reserved bytes
+const ENTRY_SIZE: usize = 16;
+
+// PCI Express memory mapped configuration table (signature "MCFG")
+#[derive(Debug, Clone, Copy)]
+pub struct Mcfg {
+    data: &'static [u8],  // table without header
+}
+
+// configuration space of buses `start_bus..=end_bus` of a segment group
+// is mapped relative to `base_address`, which is where bus 0 would be
+// even if it's not decoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct McfgEntry {
+    pub base_address: PhysAddr,
+    pub segment_group: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+impl McfgEntry {
+    // physical address of configuration space of given function, 4 KiB
+    // for each function
+    pub fn function_address(&self, bus: u8, device: u8, function: u8) -> Option<PhysAddr> {
+        if bus < self.start_bus || bus > self.end_bus || device >= 32 || function >= 8 {
+            return None;
+        }
+        let offset = u64::from(bus) << 20
+            | u64::from(device) << 15
+            | u64::from(function) << 12;
+        Some(self.base_address + offset)
+    }
+}
+
+impl Mcfg {
+    // unsafe as header must be followed by a valid MCFG
+    pub unsafe fn new(header: &'static SdtHeader) -> Self {
+        Mcfg { data: header.data() }
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = McfgEntry> {
+        let data = self.data;
+        let count = data.len().saturating_sub(ENTRIES_OFFSET) / ENTRY_SIZE;
+        (0..count).map(move |i| {
+            let offset = ENTRIES_OFFSET + i * ENTRY_SIZE;
+            McfgEntry {
+                base_address: PhysAddr::new(read(data, offset)),
+                segment_group: read(data, offset + 8),
+                start_bus: read(data, offset + 10),
+                end_bus: read(data, offset + 11),
+            }
+        })
+    }
+}
+
+
+#[cfg(all(test, not(target_os = "none")))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn function_address_is_relative_to_bus_0() {
+        let entry = McfgEntry {
+            base_address: PhysAddr::new(0xb000_0000),
+            segment_group: 0,
+            start_bus: 0x10,
+            end_bus: 0x1f,
+        };
+        assert_eq!(entry.function_address(0x10, 0, 0), Some(PhysAddr::new(0xb100_0000)));
+        assert_eq!(entry.function_address(0x11, 2, 3), Some(PhysAddr::new(0xb111_3000)));
+        assert_eq!(entry.function_address(0x0f, 0, 0), None);
+        assert_eq!(entry.function_address(0x10, 32, 0), None);
+    }
+}
@@ -0,0 +1,95 @@
+use core::alloc::{GlobalAlloc, Layout};
+
+use linked_list_allocator::LockedHeap;
+use x86_64::VirtAddr;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::{
+    Page,
+    Size4KiB,
+    Mapper,
+    FrameAllocator,
+    FrameDeallocator,
+    PageTableFlags,
+};
+
+use crate::memory::{self, Backing, MappingError};
+
+// virtual address range reserved for kernel heap, chosen to be far
+// from anything bootloader maps so it's easy to spot in page faults
+pub const HEAP_START: u64 = 0x_4444_4444_0000;
+pub const HEAP_SIZE: u64 = 100 * 1024;  // 100 KiB
+
+// heap of global allocator, locked with interrupts disabled like
+// frame allocator; otherwise a thread preempted while holding the lock
+// would make anyone allocating with interrupts disabled spin forever
+pub struct KernelHeap(LockedHeap);
+
+impl KernelHeap {
+    pub const fn empty() -> Self {
+        KernelHeap(LockedHeap::empty())
+    }
+
+    // unsafe as given memory must be mapped and unused
+    unsafe fn init(&self, start: usize, size: usize) {
+        interrupts::without_interrupts(|| self.0.lock().init(start, size));
+    }
+}
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        interrupts::without_interrupts(|| self.0.alloc(layout))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        interrupts::without_interrupts(|| self.0.dealloc(ptr, layout))
+    }
+}
+
+// map all heap pages to newly allocated frames and hand over the
+// mapped region to allocator, must be called once before using
+// `Box`, `Vec` etc.
+pub fn init_heap<A>(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut A,
+) -> Result<(), MappingError>
+    where A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>
+{
+    let page_range = {
+        let heap_start = VirtAddr::new(HEAP_START);
+        let heap_end = heap_start + HEAP_SIZE - 1u64;  // inclusive end, so that last page is not skipped
+        let heap_start_page = Page::containing_address(heap_start);
+        let heap_end_page = Page::containing_address(heap_end);
+        Page::range(heap_start_page, heap_end_page + 1)
+    };
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    memory::map_range(mapper, page_range, Backing::Anonymous, flags, frame_allocator)?;
+
+    // unsafe as heap region must be mapped and unused which is ensured above
+    unsafe { crate::ALLOCATOR.init(HEAP_START as usize, HEAP_SIZE as usize) };
+
+    Ok(())
+}
+
+
+#[cfg(all(test, target_os = "none"))]
+mod kernel_test {
+    use super::*;
+    use alloc::{boxed::Box, vec::Vec};
+
+    #[test_case]
+    fn allocates_box_and_vec() {
+        let heap_value = Box::new(41);
+        assert_eq!(*heap_value, 41);
+
+        let vec: Vec<u64> = (0..1000).collect();
+        assert_eq!(vec.iter().sum::<u64>(), 999 * 1000 / 2);
+    }
+
+    #[test_case]
+    fn reuses_freed_memory() {
+        for i in 0..HEAP_SIZE {
+            let x = Box::new(i);
+            assert_eq!(*x, i);
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::instructions::port::Port;
+use x86_64::registers::model_specific::Msr;
+
+use crate::interrupts::{self, PICS};
+use crate::memory::{self, MappingError};
+
+pub const SPURIOUS_VECTOR: u8 = 0xff;  // never gets an end of interrupt
+
+// default addresses, used if firmware doesn't tell otherwise
+pub const DEFAULT_IO_APIC_ADDR: u64 = 0xfec0_0000;
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// local APIC register offsets
+const LAPIC_ID: usize = 0x20;
+const LAPIC_TPR: usize = 0x80;  // task priority
+const LAPIC_EOI: usize = 0xb0;
+const LAPIC_SVR: usize = 0xf0;  // spurious interrupt vector
+const SVR_ENABLE: u32 = 1 << 8;
+const LAPIC_LVT_TIMER: usize = 0x320;
+const LAPIC_TIMER_INITIAL: usize = 0x380;
+const LAPIC_TIMER_DIVIDE: usize = 0x3e0;
+const LVT_NMI: u32 = 0b100 << 8;  // delivery mode
+const LVT_PERIODIC: u32 = 1 << 17;
+const DIVIDE_BY_16: u32 = 0b0011;
+const NMI_VECTOR: u32 = 2;
+
+// I/O APIC registers, accessed indirectly through select and window
+const IOAPIC_REGSEL: usize = 0x00;
+const IOAPIC_WINDOW: usize = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;  // two registers per entry
+
+const ISA_IRQS: usize = 16;
+const CASCADE_IRQ: u8 = 2;  // connects slave PIC, never raised
+
+// set once `init` is done, interrupts are acknowledged at local APIC
+// instead of PICs from then on
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static LOCAL_APIC: AtomicU64 = AtomicU64::new(0);  // virtual address of mapped registers
+
+// how a legacy ISA IRQ is connected to I/O APIC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqRoute {
+    pub gsi: u32,  // global system interrupt i.e. I/O APIC input
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ApicConfig {
+    pub local_apic_addr: PhysAddr,
+    pub io_apic_addr: PhysAddr,
+    pub io_apic_gsi_base: u32,  // first GSI handled by I/O APIC
+    pub isa_routes: [IrqRoute; ISA_IRQS],
+}
+
+impl Default for ApicConfig {
+    // local APIC address from `IA32_APIC_BASE`, I/O APIC at its usual
+    // address and ISA IRQs mapped to same GSIs, except timer which is
+    // connected to input 2 on practically every PC (and in QEMU)
+    fn default() -> Self {
+        let apic_base = unsafe { Msr::new(IA32_APIC_BASE).read() };
+        let mut isa_routes = [IrqRoute { gsi: 0, active_low: false, level_triggered: false }; ISA_IRQS];
+        for (irq, route) in isa_routes.iter_mut().enumerate() {
+            route.gsi = irq as u32;
+        }
+        isa_routes[usize::from(interrupts::TIMER_IRQ)].gsi = 2;
+
+        ApicConfig {
+            local_apic_addr: PhysAddr::new(apic_base & 0x000f_ffff_ffff_f000),
+            io_apic_addr: PhysAddr::new(DEFAULT_IO_APIC_ADDR),
+            io_apic_gsi_base: 0,
+            isa_routes,
+        }
+    }
+}
+
+// mask both PICs, enable local APIC of this CPU and route ISA IRQs
+// through I/O APIC to the same vectors PICs used, so handlers claimed
+// with `interrupts::claim_irq` keep working; call with interrupts
+// disabled
+pub fn init(config: &ApicConfig) -> Result<(), MappingError> {
+    let local_apic = memory::map_mmio(config.local_apic_addr, 0x1000)?;
+    let io_apic = IoApic { base: memory::map_mmio(config.io_apic_addr, 0x20)? };
+    LOCAL_APIC.store(local_apic.as_u64(), Ordering::Relaxed);
+
+    unsafe {
+        // PICs are still remapped, so a spurious interrupt raised
+        // before masking doesn't look like an exception
+        PICS.lock().initialize();
+        Port::<u8>::new(0x21).write(0xff);
+        Port::<u8>::new(0xa1).write(0xff);
+
+        let mut apic_base = Msr::new(IA32_APIC_BASE);
+        let value = apic_base.read();
+        apic_base.write(value | APIC_BASE_ENABLE);
+        write_local(LAPIC_TPR, 0);  // accept all interrupts
+        write_local(LAPIC_SVR, SVR_ENABLE | u32::from(SPURIOUS_VECTOR));
+    }
+
+    let apic_id = (unsafe { read_local(LAPIC_ID) } >> 24) as u8;
+    let max_entry = (io_apic.read(IOAPIC_VERSION) >> 16) & 0xff;
+    for (irq, route) in config.isa_routes.iter().enumerate() {
+        let irq = irq as u8;
+        if irq == CASCADE_IRQ || route.gsi < config.io_apic_gsi_base {
+            continue;
+        }
+        let input = route.gsi - config.io_apic_gsi_base;
+        if input > max_entry {
+            continue;  // handled by another I/O APIC
+        }
+        let entry = redirection_entry(interrupts::irq_vector(irq), apic_id, *route);
+        io_apic.write(IOAPIC_REDIRECTION_TABLE + 2 * input, entry as u32);
+        io_apic.write(IOAPIC_REDIRECTION_TABLE + 2 * input + 1, (entry >> 32) as u32);
+    }
+
+    ENABLED.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+// acknowledge interrupt currently being handled
+pub fn end_of_interrupt() {
+    unsafe { write_local(LAPIC_EOI, 0) };
+}
+
+// raise an NMI every `count` * 16 bus clocks with local APIC timer
+// e.g. for a watchdog which must run while interrupts are disabled;
+// QEMU honors delivery mode of timer LVT, real CPUs ignore it and
+// deliver fixed vector 2, which is just reported as an APIC error
+pub fn start_nmi_timer(count: u32) {
+    unsafe {
+        write_local(LAPIC_TIMER_DIVIDE, DIVIDE_BY_16);
+        write_local(LAPIC_LVT_TIMER, LVT_PERIODIC | LVT_NMI | NMI_VECTOR);
+        write_local(LAPIC_TIMER_INITIAL, count);
+    }
+}
+
+// fixed delivery to given local APIC in physical destination mode
+fn redirection_entry(vector: u8, apic_id: u8, route: IrqRoute) -> u64 {
+    let mut entry = u64::from(vector);
+    if route.active_low {
+        entry |= 1 << 13;
+    }
+    if route.level_triggered {
+        entry |= 1 << 15;
+    }
+    entry | u64::from(apic_id) << 56
+}
+
+unsafe fn read_local(offset: usize) -> u32 {
+    let base = LOCAL_APIC.load(Ordering::Relaxed) as usize;
+    ptr::read_volatile((base + offset) as *const u32)
+}
+
+unsafe fn write_local(offset: usize, value: u32) {
+    let base = LOCAL_APIC.load(Ordering::Relaxed) as usize;
+    ptr::write_volatile((base + offset) as *mut u32, value);
+}
+
+struct IoApic {
+    base: VirtAddr,
+}
+
+impl IoApic {
+    fn read(&self, register: u32) -> u32 {
+        unsafe {
+            ptr::write_volatile((self.base + IOAPIC_REGSEL).as_mut_ptr(), register);
+            ptr::read_volatile((self.base + IOAPIC_WINDOW).as_ptr())
+        }
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        unsafe {
+            ptr::write_volatile((self.base + IOAPIC_REGSEL).as_mut_ptr(), register);
+            ptr::write_volatile((self.base + IOAPIC_WINDOW).as_mut_ptr(), value);
+        }
+    }
+}
+
+
+#[cfg(all(test, not(target_os = "none")))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encodes_redirection_entry() {
+        let edge = IrqRoute { gsi: 2, active_low: false, level_triggered: false };
+        assert_eq!(redirection_entry(32, 0, edge), 32);
+
+        let level = IrqRoute { gsi: 9, active_low: true, level_triggered: true };
+        assert_eq!(redirection_entry(41, 3, level), 0x0300_0000_0000_a029);
+    }
+}
@@ -0,0 +1,66 @@
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_imports))]
+
+use core::panic::PanicInfo;
+use bootloader::{BootInfo, entry_point};
+use x86_64::VirtAddr;
+use x86_64::structures::paging::{Page, PageTableFlags, FrameAllocator, FrameDeallocator};
+use phil_opp_rust_os::{exit_qemu, QemuExitCode, serial_println, memory};
+use phil_opp_rust_os::memory::{AddressSpace, GlobalFrameAllocator, MappingError, address_space};
+
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("failed");  // to detect as failed in case of panic
+
+    serial_println!("{}", info);
+    unsafe { exit_qemu(QemuExitCode::Failed); }
+    loop {}
+}
+
+entry_point!(kernel_main);
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    unsafe {
+        memory::init(boot_info.physical_memory_offset);
+        memory::init_frame_allocator(&boot_info.memory_map, boot_info.physical_memory_offset);
+    }
+    let used_frames = || memory::with_frame_allocator(|allocator| allocator.used_frames());
+    let frames_before = used_frames();
+
+    let page: Page = Page::containing_address(VirtAddr::new(0x1000_0000_0000));  // level 4 entry unused by kernel
+    let frame = GlobalFrameAllocator.allocate_frame().unwrap();
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+    let mut space = AddressSpace::new().expect("address space creation failed");
+
+    // user page next to kernel code would end up in kernel's own tables
+    let kernel_page: Page = Page::containing_address(VirtAddr::new(kernel_main as usize as u64));
+    let user_flags = flags | PageTableFlags::USER_ACCESSIBLE;
+    assert_eq!(unsafe { space.map(kernel_page, frame, user_flags) }, Err(MappingError::SharedWithKernel));
+    assert_eq!(space.protect(kernel_page, user_flags), Err(MappingError::SharedWithKernel));
+
+    unsafe { space.map(page, frame, flags).expect("map failed") };
+    assert_eq!(space.translate_addr(page.start_address()).map(|(addr, _)| addr),
+               Some(frame.start_address()));
+
+    // page is only visible while new address space is active
+    unsafe { space.switch() };
+    let ptr: *mut u64 = page.start_address().as_mut_ptr();
+    unsafe { ptr.write_volatile(42) };
+    assert_eq!(unsafe { ptr.read_volatile() }, 42);
+    unsafe { address_space::switch_to_kernel() };
+    assert!(unsafe { memory::translate_addr(page.start_address(), boot_info.physical_memory_offset) }.is_none());
+
+    // only mapped frame should remain allocated after dropping page tables
+    drop(space);
+    assert_eq!(used_frames(), frames_before + 1);
+    GlobalFrameAllocator.deallocate_frame(frame);
+    assert_eq!(used_frames(), frames_before);
+
+    serial_println!("ok");
+
+    unsafe { exit_qemu(QemuExitCode::Success); }
+    loop {}
+}
@@ -0,0 +1,49 @@
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_imports))]
+
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use bootloader::{BootInfo, entry_point};
+use phil_opp_rust_os::{exit_qemu, QemuExitCode, serial_println, gdt, interrupts, memory, allocator, apic};
+
+static TICKS: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("failed");  // to detect as failed in case of panic
+
+    serial_println!("{}", info);
+    unsafe { exit_qemu(QemuExitCode::Failed); }
+    loop {}
+}
+
+entry_point!(kernel_main);
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator = unsafe {
+        memory::init_frame_allocator(&boot_info.memory_map, boot_info.physical_memory_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    gdt::init();
+    interrupts::init_idt();
+
+    interrupts::claim_irq(interrupts::TIMER_IRQ, || { TICKS.fetch_add(1, Ordering::SeqCst); }).unwrap();
+    apic::init(&apic::ApicConfig::default()).expect("APIC initialization failed");
+    assert!(apic::is_enabled());
+    x86_64::instructions::interrupts::enable();
+
+    // timer interrupts only keep coming if they are acknowledged at local APIC
+    while TICKS.load(Ordering::SeqCst) < 3 {
+        x86_64::instructions::hlt();
+    }
+    let vector = interrupts::irq_vector(interrupts::TIMER_IRQ);
+    assert!(interrupts::interrupt_count(vector) >= 3);
+
+    serial_println!("ok");
+
+    unsafe { exit_qemu(QemuExitCode::Success); }
+    loop {}
+}
@@ -3,7 +3,7 @@
 #![cfg_attr(test, allow(unused_imports))]
 
 use core::panic::PanicInfo;
-use phil_opp_rust_os::{exit_qemu, serial_println};
+use phil_opp_rust_os::{exit_qemu, QemuExitCode, serial_println};
 
 #[cfg(not(test))]
 #[panic_handler]
@@ -11,7 +11,7 @@ fn panic(info: &PanicInfo) -> ! {
     serial_println!("failed");  // to detect as failed in case of panic
 
     serial_println!("{}", info);
-    unsafe { exit_qemu(); }
+    unsafe { exit_qemu(QemuExitCode::Failed); }
     loop {}
 }
 
@@ -20,6 +20,6 @@ fn panic(info: &PanicInfo) -> ! {
 pub extern "C" fn _start() -> ! {
     serial_println!("ok");
 
-    unsafe { exit_qemu(); }
+    unsafe { exit_qemu(QemuExitCode::Success); }
     loop {}
 }
@@ -0,0 +1,88 @@
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_imports))]
+
+use core::panic::PanicInfo;
+use bootloader::{BootInfo, entry_point};
+use x86_64::VirtAddr;
+use x86_64::structures::paging::{Page, PageTableFlags, FrameAllocator, FrameDeallocator};
+use phil_opp_rust_os::{exit_qemu, QemuExitCode, serial_println, gdt, interrupts, memory, allocator};
+use phil_opp_rust_os::memory::{AddressSpace, GlobalFrameAllocator, address_space, cow};
+
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("failed");  // to detect as failed in case of panic
+
+    serial_println!("{}", info);
+    unsafe { exit_qemu(QemuExitCode::Failed); }
+    loop {}
+}
+
+entry_point!(kernel_main);
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator = unsafe {
+        memory::init_frame_allocator(&boot_info.memory_map, boot_info.physical_memory_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");  // reference counts live on heap
+    gdt::init();
+    interrupts::init_idt();
+    let used_frames = || memory::with_frame_allocator(|allocator| allocator.used_frames());
+    let frames_before = used_frames();
+
+    // frame is handed over to address space as it's marked owned
+    let page: Page = Page::containing_address(VirtAddr::new(0x1000_0000_0000));
+    let frame = GlobalFrameAllocator.allocate_frame().unwrap();
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | cow::OWNED;
+    let ptr: *mut u64 = page.start_address().as_mut_ptr();
+
+    // frame not owned by address space, e.g. device memory, stays as it is in both
+    let foreign_page = page + 1;
+    let foreign_frame = GlobalFrameAllocator.allocate_frame().unwrap();
+    let foreign_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let foreign_ptr: *mut u64 = foreign_page.start_address().as_mut_ptr();
+
+    let mut parent = AddressSpace::new().expect("address space creation failed");
+    unsafe { parent.map(page, frame, flags).expect("map failed") };
+    unsafe { parent.map(foreign_page, foreign_frame, foreign_flags).expect("map failed") };
+    unsafe { parent.switch() };
+    unsafe { ptr.write_volatile(1) };
+
+    let child = parent.clone_cow().expect("clone failed");
+    assert_eq!(cow::ref_count(frame.start_address()), 2);
+    assert_eq!(child.translate_addr(page.start_address()), parent.translate_addr(page.start_address()));
+    assert_eq!(cow::ref_count(foreign_frame.start_address()), 1);
+    unsafe { foreign_ptr.write_volatile(7) };  // still writable, would fault otherwise
+    assert_eq!(parent.translate_addr(foreign_page.start_address()).map(|(addr, _)| addr),
+               Some(foreign_frame.start_address()));
+
+    // write in parent copies the page
+    unsafe { ptr.write_volatile(2) };
+    assert_eq!(unsafe { ptr.read_volatile() }, 2);
+    assert_eq!(cow::ref_count(frame.start_address()), 1);
+    assert!(parent.translate_addr(page.start_address()).map(|(addr, _)| addr) != Some(frame.start_address()));
+
+    // child still sees old content, and being the only user now, writes without copying
+    unsafe { child.switch() };
+    assert_eq!(unsafe { ptr.read_volatile() }, 1);
+    unsafe { ptr.write_volatile(3) };
+    assert_eq!(child.translate_addr(page.start_address()).map(|(addr, _)| addr),
+               Some(frame.start_address()));
+    assert_eq!(unsafe { foreign_ptr.read_volatile() }, 7);
+    unsafe { foreign_ptr.write_volatile(8) };
+
+    // frame not owned is left to whoever mapped it
+    unsafe { address_space::switch_to_kernel() };
+    drop(parent);
+    drop(child);
+    assert_eq!(used_frames(), frames_before + 1);
+    GlobalFrameAllocator.deallocate_frame(foreign_frame);
+    assert_eq!(used_frames(), frames_before);
+
+    serial_println!("ok");
+
+    unsafe { exit_qemu(QemuExitCode::Success); }
+    loop {}
+}
@@ -3,7 +3,7 @@
 #![cfg_attr(test, allow(unused_imports))]
 
 use core::panic::PanicInfo;
-use phil_opp_rust_os::{exit_qemu, serial_println, interrupts};
+use phil_opp_rust_os::{exit_qemu, QemuExitCode, serial_println, interrupts};
 
 #[cfg(not(test))]
 #[panic_handler]
@@ -11,7 +11,7 @@ fn panic(info: &PanicInfo) -> ! {
     serial_println!("failed");  // to detect as failed in case of panic
 
     serial_println!("{}", info);
-    unsafe { exit_qemu(); }
+    unsafe { exit_qemu(QemuExitCode::Failed); }
     loop {}
 }
 
@@ -22,6 +22,6 @@ pub extern "C" fn _start() -> ! {
     x86_64::instructions::interrupts::int3();  // cause breakpoint exception
     serial_println!("ok");  // if this executes, exception has been handled correctly
 
-    unsafe { exit_qemu(); }
+    unsafe { exit_qemu(QemuExitCode::Success); }
     loop {}
 }
@@ -7,7 +7,8 @@ use core::panic::PanicInfo;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 use lazy_static::lazy_static;
 
-use phil_opp_rust_os::{exit_qemu, serial_println, gdt};
+use bootloader::{BootInfo, entry_point};
+use phil_opp_rust_os::{exit_qemu, QemuExitCode, serial_println, gdt, memory};
 
 #[cfg(not(test))]
 #[panic_handler]
@@ -15,15 +16,19 @@ fn panic(info: &PanicInfo) -> ! {
     serial_println!("failed");  // to detect as failed in case of panic
 
     serial_println!("{}", info);
-    unsafe { exit_qemu(); }
+    unsafe { exit_qemu(QemuExitCode::Failed); }
     loop {}
 }
 
-#[cfg(not(test))]
-#[no_mangle]
+entry_point!(kernel_main);
+
 #[allow(unconditional_recursion)]
-pub extern "C" fn _start() -> ! {
-    gdt::init();
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    unsafe {
+        memory::init(boot_info.physical_memory_offset);
+        memory::init_frame_allocator(&boot_info.memory_map, boot_info.physical_memory_offset);
+    }
+    gdt::init();  // allocates IST stacks
     init_idt();
 
     // trigger stack overflow
@@ -35,7 +40,7 @@ pub extern "C" fn _start() -> ! {
     serial_println!("failed");
     serial_println!("No exception occured");  // if this executes, exception has been handled correctly
 
-    unsafe { exit_qemu(); }
+    unsafe { exit_qemu(QemuExitCode::Failed); }
     loop {}
 }
 
@@ -61,6 +66,6 @@ extern "x86-interrupt" fn double_fault_handler(
     _error_code: u64
 ) {
     serial_println!("ok");
-    unsafe { exit_qemu(); }
+    unsafe { exit_qemu(QemuExitCode::Success); }
     loop {}
 }
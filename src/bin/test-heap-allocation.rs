@@ -0,0 +1,57 @@
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_imports))]
+
+extern crate alloc;
+
+use core::panic::PanicInfo;
+use alloc::{boxed::Box, vec::Vec, string::String, collections::BTreeMap};
+use bootloader::{BootInfo, entry_point};
+use phil_opp_rust_os::{exit_qemu, QemuExitCode, serial_println, memory, allocator};
+
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("failed");  // to detect as failed in case of panic
+
+    serial_println!("{}", info);
+    unsafe { exit_qemu(QemuExitCode::Failed); }
+    loop {}
+}
+
+entry_point!(kernel_main);
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator = unsafe {
+        memory::init_frame_allocator(&boot_info.memory_map, boot_info.physical_memory_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    let heap_value = Box::new(41);
+    assert_eq!(*heap_value, 41);
+
+    let n = 1000;
+    let mut vec = Vec::new();
+    for i in 0..n {
+        vec.push(i);
+    }
+    assert_eq!(vec.iter().sum::<u64>(), (n - 1) * n / 2);
+
+    let mut map = BTreeMap::new();
+    map.insert(String::from("key"), String::from("value"));
+    assert_eq!(map.get("key").map(|v| v.as_str()), Some("value"));
+
+    // allocating more than heap size in total only works if freed
+    // memory is reused
+    for i in 0..allocator::HEAP_SIZE {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+
+    serial_println!("ok");
+
+    unsafe { exit_qemu(QemuExitCode::Success); }
+    loop {}
+}
@@ -0,0 +1,57 @@
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_imports))]
+#![feature(asm)]
+
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use bootloader::{BootInfo, entry_point};
+use phil_opp_rust_os::{exit_qemu, QemuExitCode, serial_println, gdt, interrupts, memory, allocator};
+
+static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("failed");  // to detect as failed in case of panic
+
+    serial_println!("{}", info);
+    unsafe { exit_qemu(QemuExitCode::Failed); }
+    loop {}
+}
+
+entry_point!(kernel_main);
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator = unsafe {
+        memory::init_frame_allocator(&boot_info.memory_map, boot_info.physical_memory_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    gdt::init();
+    interrupts::init_idt();
+
+    // first free vector is right after PIC lines, `int` needs it as constant
+    let id = interrupts::claim_vector(|| { CALLS.fetch_add(1, Ordering::SeqCst); }).unwrap();
+    assert_eq!(id.vector(), 48);
+    let second = interrupts::claim_vector(|| { CALLS.fetch_add(10, Ordering::SeqCst); }).unwrap();
+    assert_eq!(second.vector(), 49);
+
+    unsafe { asm!("int 48" :::: "intel", "volatile") };
+    assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+    assert_eq!(interrupts::interrupt_count(48), 1);
+
+    // released vector is still dispatched and counted, but runs nothing
+    assert!(interrupts::release(id));
+    unsafe { asm!("int 48" :::: "intel", "volatile") };
+    assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+    assert_eq!(interrupts::interrupt_count(48), 2);
+
+    let id = interrupts::claim_vector(|| {}).unwrap();
+    assert_eq!(id.vector(), 48);
+
+    serial_println!("ok");
+
+    unsafe { exit_qemu(QemuExitCode::Success); }
+    loop {}
+}
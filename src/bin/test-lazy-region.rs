@@ -0,0 +1,57 @@
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_imports))]
+
+use core::panic::PanicInfo;
+use bootloader::{BootInfo, entry_point};
+use x86_64::VirtAddr;
+use x86_64::structures::paging::PageTableFlags;
+use phil_opp_rust_os::{exit_qemu, QemuExitCode, serial_println, gdt, interrupts, memory};
+use phil_opp_rust_os::memory::region::{self, RegionKind};
+
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("failed");  // to detect as failed in case of panic
+
+    serial_println!("{}", info);
+    unsafe { exit_qemu(QemuExitCode::Failed); }
+    loop {}
+}
+
+entry_point!(kernel_main);
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    unsafe {
+        memory::init(boot_info.physical_memory_offset);
+        memory::init_frame_allocator(&boot_info.memory_map, boot_info.physical_memory_offset);
+    }
+    gdt::init();
+    interrupts::init_idt();
+    let translate = |addr| unsafe { memory::translate_addr(addr, boot_info.physical_memory_offset) };
+
+    let start = VirtAddr::new(0x_5555_0000_0000);
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    region::register(start, 16 * 4096, RegionKind::Lazy, flags).expect("registering region failed");
+    assert!(translate(start).is_none());
+
+    // first access of each page goes through page fault handler
+    let words: *mut u64 = start.as_mut_ptr();
+    unsafe {
+        assert_eq!(words.read_volatile(), 0);  // read fault maps a zeroed frame too
+        words.write_volatile(42);
+        words.offset(15 * 512).write_volatile(43);  // last page
+    }
+    assert_eq!(unsafe { words.read_volatile() }, 42);
+    assert_eq!(unsafe { words.offset(15 * 512).read_volatile() }, 43);
+    assert!(translate(start + 4096u64).is_none());  // untouched page stays unmapped
+
+    region::unregister(start).expect("region is missing");
+    assert!(translate(start).is_none());
+    assert!(region::find(start).is_none());
+
+    serial_println!("ok");
+
+    unsafe { exit_qemu(QemuExitCode::Success); }
+    loop {}
+}
@@ -3,14 +3,14 @@
 #![cfg_attr(test, allow(unused_imports))]
 
 use core::panic::PanicInfo;
-use phil_opp_rust_os::{exit_qemu, serial_println};
+use phil_opp_rust_os::{exit_qemu, QemuExitCode, serial_println};
 
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(_info: &PanicInfo) -> ! {
     serial_println!("ok");  // to detect as success as this testing panic
 
-    unsafe { exit_qemu(); }
+    unsafe { exit_qemu(QemuExitCode::Success); }
     loop {}
 }
 
@@ -0,0 +1,38 @@
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_imports))]
+
+use core::panic::PanicInfo;
+use bootloader::{BootInfo, entry_point};
+use phil_opp_rust_os::{exit_qemu, QemuExitCode, serial_println, memory, acpi, power};
+
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("failed");  // to detect as failed in case of panic
+
+    serial_println!("{}", info);
+    unsafe { exit_qemu(QemuExitCode::Failed); }
+    loop {}
+}
+
+entry_point!(kernel_main);
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    unsafe {
+        memory::init(boot_info.physical_memory_offset);
+        memory::init_frame_allocator(&boot_info.memory_map, boot_info.physical_memory_offset);  // PM1a may be memory mapped
+    }
+    acpi::init().expect("ACPI tables not found");
+
+    // everything `power::shutdown` needs for S5 is there, shutting down
+    // for real would exit QEMU with status 0 which isn't test success
+    let pm1a = acpi::fadt().and_then(|fadt| fadt.pm1a_control_block()).expect("PM1a control block missing");
+    assert!(unsafe { pm1a.read() }.is_ok());
+    assert!(power::acpi_sleep_types().is_some(), "_S5 object not found in DSDT");
+
+    serial_println!("ok");
+
+    unsafe { exit_qemu(QemuExitCode::Success); }
+    loop {}
+}
@@ -0,0 +1,79 @@
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_macros, unused_imports))]
+#![feature(abi_x86_interrupt)]
+
+use core::panic::PanicInfo;
+use bootloader::{BootInfo, entry_point};
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use lazy_static::lazy_static;
+use phil_opp_rust_os::{exit_qemu, QemuExitCode, serial_println, gdt, memory};
+use phil_opp_rust_os::memory::stack::{self, Stack};
+
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("failed");  // to detect as failed in case of panic
+
+    serial_println!("{}", info);
+    unsafe { exit_qemu(QemuExitCode::Failed); }
+    loop {}
+}
+
+entry_point!(kernel_main);
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    unsafe {
+        memory::init(boot_info.physical_memory_offset);
+        memory::init_frame_allocator(&boot_info.memory_map, boot_info.physical_memory_offset);
+    }
+    gdt::init();
+    init_idt();
+
+    let stack = Stack::new(2, "test").expect("allocating stack failed");
+    let bottom: *mut u64 = stack.bottom().as_mut_ptr();
+    unsafe {
+        stack.top().as_mut_ptr::<u64>().offset(-1).write_volatile(42);  // whole stack is usable
+        bottom.write_volatile(42);
+        bottom.offset(-1).write_volatile(42);  // first word of guard page
+    }
+
+    serial_println!("failed");
+    serial_println!("No exception occured");
+
+    unsafe { exit_qemu(QemuExitCode::Failed); }
+    loop {}
+}
+
+// IDT needs to be redefined as we need to have a different handler
+// for testing and existing one is not mutable
+lazy_static! {
+    static ref IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(page_fault_handler);
+        idt
+    };
+}
+
+fn init_idt() {
+    IDT.load();
+}
+
+extern "x86-interrupt" fn page_fault_handler(
+    _stack_frame: &mut InterruptStackFrame,
+    _error_code: PageFaultErrorCode,
+) {
+    let exit_code = match stack::guard_owner(Cr2::read()) {
+        Some("test") => {
+            serial_println!("ok");
+            QemuExitCode::Success
+        },
+        owner => {
+            serial_println!("failed\nGuard page owner: {:?}", owner);
+            QemuExitCode::Failed
+        },
+    };
+    unsafe { exit_qemu(exit_code); }
+    loop {}
+}
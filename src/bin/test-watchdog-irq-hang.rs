@@ -0,0 +1,44 @@
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_imports))]
+
+use core::panic::PanicInfo;
+use bootloader::{BootInfo, entry_point};
+use phil_opp_rust_os::{
+    exit_qemu, QemuExitCode, print, serial_println, gdt, interrupts, memory, allocator, apic, testing, vga_buffer,
+};
+
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("failed");  // to detect as failed in case of panic
+
+    serial_println!("{}", info);
+    unsafe { exit_qemu(QemuExitCode::Failed); }
+    loop {}
+}
+
+entry_point!(kernel_main);
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator = unsafe {
+        memory::init_frame_allocator(&boot_info.memory_map, boot_info.physical_memory_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    gdt::init();
+    interrupts::init_idt();
+
+    apic::init(&apic::ApicConfig::default()).expect("APIC initialization failed");
+    testing::start_watchdog().expect("claiming timer IRQ failed");
+    testing::expect_timeout();  // watchdog exits with success once it notices the hang
+
+    // timer handler deadlocks on VGA writer held by interrupted code,
+    // with interrupts disabled, so only watchdog NMI can end this
+    interrupts::claim_irq(interrupts::TIMER_IRQ, || print!(".")).unwrap();
+    let _writer = vga_buffer::WRITER.lock();
+    x86_64::instructions::interrupts::enable();
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
@@ -0,0 +1,49 @@
+use crate::println;
+use crate::thread::{self, policy, Priority, State};
+
+// run a command typed on keyboard, see `help` for what's there
+pub fn run(line: &str) {
+    let mut words = line.split_whitespace();
+    match (words.next(), words.next()) {
+        (None, _) => {},
+        (Some("help"), _) => help(),
+        (Some("ps"), _) => ps(),
+        (Some("sched"), None) => println!("policy: {}", thread::policy_name()),
+        (Some("sched"), Some(name)) => match policy::by_name(name) {
+            Some(policy) => {
+                thread::set_policy(policy);
+                println!("policy: {}", thread::policy_name());
+            },
+            None => println!("unknown policy {}, one of {:?}", name, policy::NAMES),
+        },
+        (Some(command), _) => println!("unknown command {}, try help", command),
+    }
+}
+
+fn help() {
+    println!("ps                 list threads with their CPU time and switches");
+    println!("sched [POLICY]     show or set scheduling policy, one of {:?}", policy::NAMES);
+}
+
+fn ps() {
+    println!("policy: {}", thread::policy_name());
+    println!("{:>4} {:<12} {:<10} {:<8} {:>8} {:>8} {:>8}",
+             "ID", "NAME", "STATE", "PRIO", "CPU MS", "SWITCH", "PREEMPT");
+    for info in thread::threads() {
+        let state = match info.state {
+            State::Ready => "ready",
+            State::Running => "running",
+            State::Sleeping(_) => "sleeping",
+            State::Joining(_) => "joining",
+            State::Dead => "dead",
+        };
+        let priority = match info.priority {
+            Priority::High => "high",
+            Priority::Normal => "normal",
+            Priority::Low => "low",
+        };
+        println!("{:>4} {:<12} {:<10} {:<8} {:>8} {:>8} {:>8}",
+                 info.id.as_u64(), info.name, state, priority,
+                 info.stats.cpu_nanos / 1_000_000, info.stats.context_switches(), info.stats.preemptions);
+    }
+}
@@ -0,0 +1,401 @@
+// function with x86-interrupt calling convention doesn't work as of
+// now if compiled for Windows target due to a bug in LLVM
+// (`cargo test`), same goes for naked functions below
+#![cfg(not(windows))]
+
+use core::fmt;
+use core::mem;
+
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrameValue, PageFaultErrorCode};
+use x86_64::registers::control::Cr2;
+
+use crate::{println, serial_println, memory, gdt, hlt_loop, testing};
+
+// print to both VGA buffer and serial, so reports are visible on
+// screen and in test output
+macro_rules! report {
+    ($($arg:tt)*) => {{
+        println!($($arg)*);
+        serial_println!($($arg)*);
+    }};
+}
+
+// Naked entry stub for given handler. `x86-interrupt` functions don't
+// give access to registers of interrupted code, so the stub saves all
+// general purpose registers on stack, passes pointer to them (along
+// with error code and stack frame pushed by CPU) to the handler and
+// restores them before returning. Exceptions without error code get a
+// zero pushed instead, so that every handler sees the same layout.
+macro_rules! entry_stub {
+    ($handler:ident) => {{
+        #[naked]
+        extern "C" fn stub() -> ! {
+            unsafe {
+                asm!("push 0" :::: "intel", "volatile");
+                save_registers_and_call!($handler);
+            }
+        }
+        stub
+    }};
+    ($handler:ident, error_code) => {{
+        #[naked]
+        extern "C" fn stub() -> ! {
+            unsafe {
+                save_registers_and_call!($handler);
+            }
+        }
+        stub
+    }};
+}
+
+// stack is 16 byte aligned before CPU pushes stack frame (5 words),
+// after error code and 15 registers it's off by 8, so it's aligned
+// again before calling handler
+macro_rules! save_registers_and_call {
+    ($handler:ident) => {{
+        asm!("push rax
+              push rbx
+              push rcx
+              push rdx
+              push rsi
+              push rdi
+              push rbp
+              push r8
+              push r9
+              push r10
+              push r11
+              push r12
+              push r13
+              push r14
+              push r15
+
+              mov rdi, rsp
+              sub rsp, 8
+              call $0
+              add rsp, 8
+
+              pop r15
+              pop r14
+              pop r13
+              pop r12
+              pop r11
+              pop r10
+              pop r9
+              pop r8
+              pop rbp
+              pop rdi
+              pop rsi
+              pop rdx
+              pop rcx
+              pop rbx
+              pop rax
+              add rsp, 8
+              iretq"
+             :: "i"($handler as extern "C" fn(&mut ExceptionContext))
+             : "rdi" : "intel", "volatile");
+        ::core::intrinsics::unreachable();
+    }};
+}
+
+// IDT entries expect `x86-interrupt` functions, stubs follow the same
+// convention (they return with `iretq`), so only the type differs
+macro_rules! set_handler {
+    ($entry:expr, $handler:ident) => {
+        $entry.set_handler_fn(unsafe { mem::transmute(entry_stub!($handler) as extern "C" fn() -> !) })
+    };
+    ($entry:expr, $handler:ident, error_code) => {
+        $entry.set_handler_fn(unsafe {
+            mem::transmute(entry_stub!($handler, error_code) as extern "C" fn() -> !)
+        })
+    };
+}
+
+// general purpose registers in the order they are pushed by entry stub
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Registers {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+}
+
+// everything on stack of an exception handler, changes are written
+// back when handler returns
+#[derive(Debug)]
+#[repr(C)]
+pub struct ExceptionContext {
+    pub registers: Registers,
+    pub error_code: u64,  // zero for exceptions which don't push one
+    pub stack_frame: InterruptStackFrameValue,
+}
+
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let registers = [
+            ("rax", self.rax), ("rbx", self.rbx), ("rcx", self.rcx),
+            ("rdx", self.rdx), ("rsi", self.rsi), ("rdi", self.rdi),
+            ("rbp", self.rbp), ("r8", self.r8), ("r9", self.r9),
+            ("r10", self.r10), ("r11", self.r11), ("r12", self.r12),
+            ("r13", self.r13), ("r14", self.r14), ("r15", self.r15),
+        ];
+        for (i, (name, value)) in registers.iter().enumerate() {
+            write!(f, "{:>3}: {:#018x}", name, value)?;
+            f.write_str(if i % 3 == 2 { "\n" } else { "  " })?;  // 3 per line to fit VGA buffer
+        }
+        Ok(())
+    }
+}
+
+// error code of exceptions caused by a segment selector i.e. #TS, #NP,
+// #SS and #GP, zero if exception is not related to a segment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelectorErrorCode(pub u64);
+
+impl SelectorErrorCode {
+    // set if exception was caused by an event external to the program
+    pub fn external(self) -> bool {
+        self.0 & 1 != 0
+    }
+
+    pub fn table(self) -> &'static str {
+        match (self.0 >> 1) & 0b11 {
+            0 => "GDT",
+            2 => "LDT",
+            _ => "IDT",  // both 1 and 3 mean IDT
+        }
+    }
+
+    pub fn index(self) -> u64 {
+        (self.0 >> 3) & 0x1fff
+    }
+}
+
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 == 0 {
+            return write!(f, "0 (not related to a segment)");
+        }
+        write!(f, "{:#x} ({} index {}, external: {})", self.0, self.table(), self.index(),
+               self.external())
+    }
+}
+
+// install handlers for all architectural exceptions, some of them get
+// a separate stack from IST so they still work if kernel stack overflows
+pub fn set_handlers(idt: &mut InterruptDescriptorTable) {
+    set_handler!(idt.divide_by_zero, divide_by_zero_handler);
+    set_handler!(idt.debug, debug_handler);
+    set_handler!(idt.breakpoint, breakpoint_handler);
+    set_handler!(idt.overflow, overflow_handler);
+    set_handler!(idt.bound_range_exceeded, bound_range_exceeded_handler);
+    set_handler!(idt.invalid_opcode, invalid_opcode_handler);
+    set_handler!(idt.device_not_available, device_not_available_handler);
+    set_handler!(idt.invalid_tss, invalid_tss_handler, error_code);
+    set_handler!(idt.segment_not_present, segment_not_present_handler, error_code);
+    set_handler!(idt.stack_segment_fault, stack_segment_fault_handler, error_code);
+    set_handler!(idt.general_protection_fault, general_protection_fault_handler, error_code);
+    set_handler!(idt.x87_floating_point, x87_floating_point_handler);
+    set_handler!(idt.alignment_check, alignment_check_handler, error_code);
+    set_handler!(idt.simd_floating_point, simd_floating_point_handler);
+    set_handler!(idt.virtualization, virtualization_handler);
+    set_handler!(idt.security_exception, security_exception_handler, error_code);
+
+    // setting stack index is unsafe as index must be valid and not used
+    // by any other exception
+    let options = set_handler!(idt.double_fault, double_fault_handler, error_code);
+    unsafe { options.set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX); }
+    let options = set_handler!(idt.non_maskable_interrupt, nmi_handler);
+    unsafe { options.set_stack_index(gdt::NMI_IST_INDEX); }
+    let options = set_handler!(idt.machine_check, machine_check_handler);
+    unsafe { options.set_stack_index(gdt::MACHINE_CHECK_IST_INDEX); }
+    let options = set_handler!(idt.page_fault, page_fault_handler, error_code);
+    unsafe { options.set_stack_index(gdt::PAGE_FAULT_IST_INDEX); }
+}
+
+// print stack frame and registers of interrupted code
+fn dump(context: &ExceptionContext) {
+    report!("{:#?}", context.stack_frame);
+    report!("{}", context.registers);
+}
+
+// report exception which can't be recovered from and halt
+fn fatal(name: &str, context: &ExceptionContext) -> ! {
+    report!("Exception: {}", name);
+    dump(context);
+    hlt_loop();
+}
+
+// report exception with a selector error code and halt
+fn fatal_with_selector(name: &str, context: &ExceptionContext) -> ! {
+    report!("Exception: {}", name);
+    report!("Error Code: {}", SelectorErrorCode(context.error_code));
+    dump(context);
+    hlt_loop();
+}
+
+extern "C" fn divide_by_zero_handler(context: &mut ExceptionContext) {
+    fatal("Divide Error", context);
+}
+
+// single step or hardware breakpoint, execution continues
+extern "C" fn debug_handler(context: &mut ExceptionContext) {
+    report!("Exception: Debug");
+    dump(context);
+}
+
+extern "C" fn breakpoint_handler(context: &mut ExceptionContext) {
+    report!("Exception: Breakpoint");
+    dump(context);
+}
+
+// raised by `into`, it's a trap so execution can continue after it
+extern "C" fn overflow_handler(context: &mut ExceptionContext) {
+    report!("Exception: Overflow");
+    dump(context);
+}
+
+extern "C" fn bound_range_exceeded_handler(context: &mut ExceptionContext) {
+    fatal("Bound Range Exceeded", context);
+}
+
+extern "C" fn invalid_opcode_handler(context: &mut ExceptionContext) {
+    fatal("Invalid Opcode", context);
+}
+
+extern "C" fn device_not_available_handler(context: &mut ExceptionContext) {
+    fatal("Device Not Available", context);
+}
+
+extern "C" fn double_fault_handler(context: &mut ExceptionContext) {
+    report!("Exception: Double Fault");
+    // a page fault on guard page can't be handled on the overflowed
+    // stack, so it ends up here with `Cr2` still holding the address
+    if let Some(name) = memory::stack::guard_owner(Cr2::read()) {
+        report!("Stack overflow: {} stack", name);
+    }
+    dump(context);
+    hlt_loop();
+}
+
+extern "C" fn invalid_tss_handler(context: &mut ExceptionContext) {
+    fatal_with_selector("Invalid TSS", context);
+}
+
+extern "C" fn segment_not_present_handler(context: &mut ExceptionContext) {
+    fatal_with_selector("Segment Not Present", context);
+}
+
+extern "C" fn stack_segment_fault_handler(context: &mut ExceptionContext) {
+    fatal_with_selector("Stack-Segment Fault", context);
+}
+
+extern "C" fn general_protection_fault_handler(context: &mut ExceptionContext) {
+    fatal_with_selector("General Protection Fault", context);
+}
+
+extern "C" fn page_fault_handler(context: &mut ExceptionContext) {
+    let addr = Cr2::read();
+    let error_code = PageFaultErrorCode::from_bits_truncate(context.error_code);
+    if memory::handle_page_fault(addr, error_code) {
+        return;  // fault is resolved, faulting instruction is executed again
+    }
+
+    report!("Exception: Page Fault");
+    report!("Accessed Address: {:?}", addr);
+    report!("Error Code: {:?}", error_code);
+    report!("  page present: {}", error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION));
+    report!("  write access: {}", error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE));
+    report!("  user mode: {}", error_code.contains(PageFaultErrorCode::USER_MODE));
+    report!("  reserved bit set: {}", error_code.contains(PageFaultErrorCode::MALFORMED_TABLE));
+    report!("  instruction fetch: {}", error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH));
+    if let Some(region) = memory::region::find(addr) {
+        report!("  inside region: {:?}", region);
+    }
+    if let Some(name) = memory::stack::guard_owner(addr) {
+        report!("Stack overflow: {} stack", name);
+    }
+    dump(context);
+    hlt_loop();
+}
+
+extern "C" fn x87_floating_point_handler(context: &mut ExceptionContext) {
+    fatal("x87 Floating-Point Exception", context);
+}
+
+extern "C" fn alignment_check_handler(context: &mut ExceptionContext) {
+    fatal("Alignment Check", context);
+}
+
+extern "C" fn machine_check_handler(context: &mut ExceptionContext) {
+    fatal("Machine Check", context);  // hardware error, can't be recovered from
+}
+
+extern "C" fn simd_floating_point_handler(context: &mut ExceptionContext) {
+    fatal("SIMD Floating-Point Exception", context);
+}
+
+extern "C" fn virtualization_handler(context: &mut ExceptionContext) {
+    fatal("Virtualization Exception", context);
+}
+
+extern "C" fn security_exception_handler(context: &mut ExceptionContext) {
+    report!("Exception: Security Exception");
+    report!("Error Code: {:#x}", context.error_code);
+    dump(context);
+    hlt_loop();
+}
+
+// NMI is usually a hardware failure or watchdog, nothing to fix here
+extern "C" fn nmi_handler(context: &mut ExceptionContext) {
+    if testing::watchdog_nmi(&context.stack_frame) {
+        return;
+    }
+    report!("Exception: Non-Maskable Interrupt");
+    dump(context);
+}
+
+
+#[cfg(all(test, not(target_os = "none")))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decodes_selector_error_code() {
+        let code = SelectorErrorCode(0x1d);  // index 3 in LDT, external
+        assert!(code.external());
+        assert_eq!(code.table(), "LDT");
+        assert_eq!(code.index(), 3);
+
+        let code = SelectorErrorCode(0x42);  // index 8 in IDT
+        assert!(!code.external());
+        assert_eq!(code.table(), "IDT");
+        assert_eq!(code.index(), 8);
+    }
+
+    #[test]
+    fn context_matches_stack_layout() {
+        // 15 registers and error code pushed by stub, 5 words by CPU
+        assert_eq!(mem::size_of::<ExceptionContext>(), (15 + 1 + 5) * 8);
+    }
+}
+
+
+#[cfg(all(test, target_os = "none"))]
+mod kernel_test {
+    #[test_case]
+    fn breakpoint_resumes() {
+        x86_64::instructions::interrupts::int3();
+    }
+}
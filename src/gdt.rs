@@ -3,21 +3,37 @@ use x86_64;
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector};
 
+use crate::memory::stack::Stack;
+
 // declared pub and outside below block as this will used for setting
 // IST index in exception handler too
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+pub const PAGE_FAULT_IST_INDEX: u16 = 3;  // so that guard page faults don't need the overflowed stack
+
+// size of each IST stack in pages, handlers print a lot so these are
+// bigger than a single page
+pub const DOUBLE_FAULT_STACK_PAGES: u64 = 4;
+pub const NMI_STACK_PAGES: u64 = 4;
+pub const MACHINE_CHECK_STACK_PAGES: u64 = 4;
+pub const PAGE_FAULT_STACK_PAGES: u64 = 8;  // resolving faults maps pages, which needs more
 
 lazy_static! {
     static ref TSS: TaskStateSegment = {
         let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];  // stack to use for double fault handler
-
-            let stack_start = x86_64::VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end  // stacks start filling from higher address to lower
-        };
+        let stacks = [
+            (DOUBLE_FAULT_IST_INDEX, DOUBLE_FAULT_STACK_PAGES, "double fault"),
+            (NMI_IST_INDEX, NMI_STACK_PAGES, "NMI"),
+            (MACHINE_CHECK_IST_INDEX, MACHINE_CHECK_STACK_PAGES, "machine check"),
+            (PAGE_FAULT_IST_INDEX, PAGE_FAULT_STACK_PAGES, "page fault"),
+        ];
+        // every stack has a guard page below it and is never freed; `top`
+        // is stored as stacks start filling from higher address to lower
+        for &(index, pages, name) in stacks.iter() {
+            let stack = Stack::new(pages, name).expect("allocating IST stack failed");
+            tss.interrupt_stack_table[index as usize] = stack.leak();
+        }
         tss
     };
 }
@@ -36,6 +52,8 @@ struct Selectors {
     tss_selector: SegmentSelector,
 }
 
+// IST stacks are allocated here, so `memory::init` and
+// `memory::init_frame_allocator` must be called before
 pub fn init() {
     GDT.0.load();
 
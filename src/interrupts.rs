@@ -3,18 +3,23 @@
 // (`cargo test`)
 #![cfg(not(windows))]
 
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
-use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::InterruptDescriptorTable;
 use lazy_static::lazy_static;
 use pic8259_simple::ChainedPics;
 use spin;
 
-use crate::{print, println};
+use crate::{exceptions, task};
+
+pub use self::registry::{
+    claim_irq, claim_vector, release, interrupt_count, irq_vector, interrupted_frame, HandlerId, ClaimError,
+};
+
+mod registry;
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
-pub const TIMER_INTERRUPT_ID: u8 = PIC_1_OFFSET;  // timer is on line 0
-pub const KEYBOARD_INTERRUPT_ID: u8 = PIC_1_OFFSET + 1;  // keyboard in on line 1
+pub const TIMER_IRQ: u8 = 0;
+pub const KEYBOARD_IRQ: u8 = 1;
 
 pub static PICS: spin::Mutex<ChainedPics> = spin::Mutex::new(unsafe {
     ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET)  // unsafe as compiler can't guarantee offset validity
@@ -23,14 +28,8 @@ pub static PICS: spin::Mutex<ChainedPics> = spin::Mutex::new(unsafe {
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        unsafe {
-            idt.double_fault.set_handler_fn(double_fault_handler)
-                .set_stack_index(crate::gdt::DOUBLE_FAULT_IST_INDEX);
-        }
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        idt[usize::from(TIMER_INTERRUPT_ID)].set_handler_fn(timer_interrupt_handler);
-        idt[usize::from(KEYBOARD_INTERRUPT_ID)].set_handler_fn(keyboard_interrupt_handler);
+        exceptions::set_handlers(&mut idt);
+        registry::set_handlers(&mut idt);  // handlers for rest of the vectors are added at runtime
         idt
     };
 }
@@ -39,54 +38,17 @@ pub fn init_idt() {
     IDT.load();
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut InterruptStackFrame) {
-    println!("Exception: Breakpoint\n{:#?}", stack_frame);
-}
-
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: &mut InterruptStackFrame,
-    _error_code: u64,
-) {
-    println!("Exception: Double Fault\n{:#?}", stack_frame);
-    crate::hlt_loop();
-}
-
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: &mut InterruptStackFrame,
-    _error_code: PageFaultErrorCode,
-) {
-    println!("Exception: Page Fault\n{:#?}", stack_frame);
-    println!("Accessed Address: {:?}", Cr2::read());
-
-    crate::hlt_loop();
-}
-
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
-    print!(".");
-    unsafe { PICS.lock().notify_end_of_interrupt(TIMER_INTERRUPT_ID); }
+// claim IRQs of devices kernel drives itself, needs heap
+pub fn init_devices() {
+    claim_irq(KEYBOARD_IRQ, keyboard_handler).expect("claiming keyboard IRQ failed");
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+// only reads scancode, it's decoded by `task::keyboard::print_keypresses`
+// outside interrupt context
+fn keyboard_handler() {
     use x86_64::instructions::port::Port;
-    use pc_keyboard::{Keyboard, ScancodeSet1, DecodedKey, layouts};
 
-    lazy_static! {
-        static ref KEYBOARD: spin::Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            spin::Mutex::new(Keyboard::new(layouts::Us104Key, ScancodeSet1));
-    }
-
-    let mut keyboard = KEYBOARD.lock();  // lock mutex before reading scancode
     let port = Port::new(0x60);
     let scancode: u8 = unsafe { port.read() };
-
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {  // add_byte returns Result<Option<KeyEvent>>
-        if let Some(key) = keyboard.process_keyevent(key_event) {  // process_keyevent returns Option<DecodedKey>
-            match key {  // DecodedKey has two variants - Unicode(char) and RawKey(KeyCode)
-                DecodedKey::Unicode(c) => print!("{}", c),
-                DecodedKey::RawKey(c) => print!("{:?}", c),
-            }
-        }
-    }
-
-    unsafe { PICS.lock().notify_end_of_interrupt(KEYBOARD_INTERRUPT_ID); }
+    task::keyboard::add_scancode(scancode);
 }
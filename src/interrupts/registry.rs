@@ -0,0 +1,265 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicPtr, Ordering};
+use core::ptr;
+
+use spin::Mutex;
+use lazy_static::lazy_static;
+use x86_64::instructions::interrupts;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, InterruptStackFrameValue, HandlerFunc};
+
+use super::{PICS, PIC_1_OFFSET, PIC_2_OFFSET};
+use crate::apic::{self, SPURIOUS_VECTOR};
+use crate::thread;
+
+// one handler per vector which tells dispatcher the vector number, as
+// `x86-interrupt` handlers don't get to know it otherwise
+macro_rules! dispatch_stubs {
+    ($($vector:expr,)*) => {
+        [$({
+            extern "x86-interrupt" fn stub(stack_frame: &mut InterruptStackFrame) {
+                dispatch($vector, stack_frame);
+            }
+            stub as HandlerFunc
+        }),*]
+    };
+}
+
+const FIRST_VECTOR: u8 = 32;  // ones below are CPU exceptions
+const FIRST_FREE_VECTOR: u8 = PIC_2_OFFSET + 8;  // ones below are used by PIC lines
+const IRQ_LINES: u8 = 16;
+
+type Handler = Box<dyn FnMut() + Send>;
+
+// stack frame of interrupt being dispatched, null otherwise; handlers
+// run with interrupts disabled, so there is only one at a time
+static INTERRUPTED_FRAME: AtomicPtr<InterruptStackFrame> = AtomicPtr::new(ptr::null_mut());
+
+lazy_static! {
+    static ref REGISTRY: Mutex<Registry> = Mutex::new(Registry::new());
+}
+
+// returned when claiming an IRQ or vector, needed to release it again
+#[derive(Debug, PartialEq, Eq)]
+pub struct HandlerId {
+    vector: u8,
+    id: u64,
+}
+
+impl HandlerId {
+    pub fn vector(&self) -> u8 {
+        self.vector
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClaimError {
+    InvalidIrq,
+    VectorInUse,  // vector is claimed exclusively
+    NoFreeVector,
+}
+
+struct Vector {
+    handlers: Vec<(u64, Handler)>,
+    shared: bool,  // IRQ lines can be shared, vectors claimed by `claim_vector` can't
+}
+
+// handlers by vector and number of interrupts received on each vector,
+// counts include interrupts nobody handled
+struct Registry {
+    vectors: BTreeMap<u8, Vector>,
+    counts: [u64; 256],
+    next_id: u64,
+}
+
+impl Registry {
+    fn new() -> Self {
+        Registry { vectors: BTreeMap::new(), counts: [0; 256], next_id: 0 }
+    }
+
+    fn add(&mut self, vector: u8, shared: bool, handler: Handler) -> Result<HandlerId, ClaimError> {
+        let entry = self.vectors.entry(vector).or_insert_with(|| Vector { handlers: Vec::new(), shared });
+        let shareable = entry.shared && shared;
+        if !entry.handlers.is_empty() && !shareable {
+            return Err(ClaimError::VectorInUse);
+        }
+        entry.shared = shared;
+
+        let id = self.next_id;
+        self.next_id += 1;
+        entry.handlers.push((id, handler));
+        Ok(HandlerId { vector, id })
+    }
+
+    fn free_vector(&self) -> Option<u8> {
+        (FIRST_FREE_VECTOR..SPURIOUS_VECTOR).find(|vector| !self.vectors.contains_key(vector))
+    }
+
+    fn remove(&mut self, handler_id: HandlerId) -> bool {
+        let entry = match self.vectors.get_mut(&handler_id.vector) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        let len = entry.handlers.len();
+        entry.handlers.retain(|&(id, _)| id != handler_id.id);
+        let removed = entry.handlers.len() < len;
+        if entry.handlers.is_empty() {
+            self.vectors.remove(&handler_id.vector);
+        }
+        removed
+    }
+
+    // run all handlers of given vector, returns how many were run
+    fn dispatch(&mut self, vector: u8) -> usize {
+        self.counts[vector as usize] += 1;
+        match self.vectors.get_mut(&vector) {
+            Some(entry) => {
+                for (_, handler) in entry.handlers.iter_mut() {
+                    handler();
+                }
+                entry.handlers.len()
+            },
+            None => 0,
+        }
+    }
+}
+
+// vector given legacy IRQ line is remapped to by PIC
+pub fn irq_vector(irq: u8) -> u8 {
+    PIC_1_OFFSET + irq
+}
+
+// add handler for given IRQ line (0-15), an IRQ can be shared by
+// several handlers, all of them are run on every interrupt; handlers
+// run with interrupts disabled and must not claim or release handlers
+pub fn claim_irq<F>(irq: u8, handler: F) -> Result<HandlerId, ClaimError>
+    where F: FnMut() + Send + 'static
+{
+    if irq >= IRQ_LINES {
+        return Err(ClaimError::InvalidIrq);
+    }
+    interrupts::without_interrupts(|| {  // dispatching locks registry too
+        REGISTRY.lock().add(irq_vector(irq), true, Box::new(handler))
+    })
+}
+
+// claim a vector which is not used by anything else for given handler
+// exclusively e.g. for message signaled interrupts, vector is returned
+// as part of handler id
+pub fn claim_vector<F>(handler: F) -> Result<HandlerId, ClaimError>
+    where F: FnMut() + Send + 'static
+{
+    interrupts::without_interrupts(|| {
+        let mut registry = REGISTRY.lock();
+        let vector = registry.free_vector().ok_or(ClaimError::NoFreeVector)?;
+        registry.add(vector, false, Box::new(handler))
+    })
+}
+
+// remove handler added by `claim_irq` or `claim_vector`, vector can be
+// claimed again once all its handlers are released
+pub fn release(handler_id: HandlerId) -> bool {
+    interrupts::without_interrupts(|| REGISTRY.lock().remove(handler_id))
+}
+
+// number of interrupts received on given vector so far
+pub fn interrupt_count(vector: u8) -> u64 {
+    interrupts::without_interrupts(|| REGISTRY.lock().counts[vector as usize])
+}
+
+// where the interrupted code was, only available to handlers while
+// they run e.g. to report what a watchdog interrupted
+pub fn interrupted_frame() -> Option<InterruptStackFrameValue> {
+    let frame = INTERRUPTED_FRAME.load(Ordering::SeqCst);
+    unsafe { frame.as_ref() }.map(|frame| (**frame).clone())
+}
+
+// point all vectors after exceptions to dispatcher
+pub(super) fn set_handlers(idt: &mut InterruptDescriptorTable) {
+    let stubs = dispatch_stubs!(
+        32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47,
+        48, 49, 50, 51, 52, 53, 54, 55, 56, 57, 58, 59, 60, 61, 62, 63,
+        64, 65, 66, 67, 68, 69, 70, 71, 72, 73, 74, 75, 76, 77, 78, 79,
+        80, 81, 82, 83, 84, 85, 86, 87, 88, 89, 90, 91, 92, 93, 94, 95,
+        96, 97, 98, 99, 100, 101, 102, 103, 104, 105, 106, 107, 108, 109, 110, 111,
+        112, 113, 114, 115, 116, 117, 118, 119, 120, 121, 122, 123, 124, 125, 126, 127,
+        128, 129, 130, 131, 132, 133, 134, 135, 136, 137, 138, 139, 140, 141, 142, 143,
+        144, 145, 146, 147, 148, 149, 150, 151, 152, 153, 154, 155, 156, 157, 158, 159,
+        160, 161, 162, 163, 164, 165, 166, 167, 168, 169, 170, 171, 172, 173, 174, 175,
+        176, 177, 178, 179, 180, 181, 182, 183, 184, 185, 186, 187, 188, 189, 190, 191,
+        192, 193, 194, 195, 196, 197, 198, 199, 200, 201, 202, 203, 204, 205, 206, 207,
+        208, 209, 210, 211, 212, 213, 214, 215, 216, 217, 218, 219, 220, 221, 222, 223,
+        224, 225, 226, 227, 228, 229, 230, 231, 232, 233, 234, 235, 236, 237, 238, 239,
+        240, 241, 242, 243, 244, 245, 246, 247, 248, 249, 250, 251, 252, 253, 254, 255,
+    );
+    for (i, &stub) in stubs.iter().enumerate() {
+        idt[usize::from(FIRST_VECTOR) + i].set_handler_fn(stub);
+    }
+}
+
+fn dispatch(vector: u8, stack_frame: &mut InterruptStackFrame) {
+    INTERRUPTED_FRAME.store(stack_frame, Ordering::SeqCst);
+    REGISTRY.lock().dispatch(vector);
+    INTERRUPTED_FRAME.store(ptr::null_mut(), Ordering::SeqCst);
+    if vector != SPURIOUS_VECTOR {  // spurious interrupts must not be acknowledged
+        end_of_interrupt(vector);
+    }
+    thread::preempt();  // may switch to another thread, so it's done last
+}
+
+// PIC ignores vectors it doesn't handle, so it's notified for every
+// vector until APIC takes over
+fn end_of_interrupt(vector: u8) {
+    if apic::is_enabled() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe { PICS.lock().notify_end_of_interrupt(vector); }
+    }
+}
+
+
+#[cfg(all(test, not(target_os = "none")))]
+mod test {
+    use super::*;
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn shared_irq_runs_all_handlers() {
+        let mut registry = Registry::new();
+        let calls = Arc::new(AtomicUsize::new(0));
+        for _ in 0..2 {
+            let calls = calls.clone();
+            registry.add(33, true, Box::new(move || { calls.fetch_add(1, Ordering::SeqCst); })).unwrap();
+        }
+
+        assert_eq!(registry.dispatch(33), 2);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(registry.counts[33], 1);
+    }
+
+    #[test]
+    fn exclusive_vector_is_not_shared() {
+        let mut registry = Registry::new();
+        let vector = registry.free_vector().unwrap();
+        assert_eq!(vector, FIRST_FREE_VECTOR);
+        registry.add(vector, false, Box::new(|| {})).unwrap();
+
+        assert_eq!(registry.add(vector, true, Box::new(|| {})), Err(ClaimError::VectorInUse));
+        assert_eq!(registry.free_vector(), Some(vector + 1));
+    }
+
+    #[test]
+    fn released_vector_is_free_again() {
+        let mut registry = Registry::new();
+        let id = registry.add(FIRST_FREE_VECTOR, false, Box::new(|| {})).unwrap();
+        let stale = HandlerId { vector: id.vector, id: id.id };
+
+        assert!(registry.remove(id));
+        assert!(!registry.remove(stale));
+        assert_eq!(registry.free_vector(), Some(FIRST_FREE_VECTOR));
+        assert_eq!(registry.dispatch(FIRST_FREE_VECTOR), 0);  // still counted
+        assert_eq!(registry.counts[FIRST_FREE_VECTOR as usize], 1);
+    }
+}
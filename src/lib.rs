@@ -1,18 +1,75 @@
-#![cfg_attr(not(test), no_std)]  // don't link std library as we won't have it
+#![cfg_attr(any(not(test), target_os = "none"), no_std)]  // don't link std library as we won't have it, std is only used by unit tests on host
+#![cfg_attr(all(test, target_os = "none"), no_main)]  // kernel tests have their own entry point
 #![feature(abi_x86_interrupt)]
+#![feature(alloc_error_handler)]
+#![feature(asm)]
+#![feature(naked_functions)]
+#![feature(core_intrinsics)]
+#![feature(atomic_min_max)]
+#![feature(global_asm)]
+#![feature(custom_test_frameworks)]
+// kernel tests i.e. `#[test_case]`s are run inside QEMU by our own
+// runner, unit tests on host i.e. `#[test]`s still use std's runner
+#![cfg_attr(target_os = "none", test_runner(crate::testing::runner))]
+#![cfg_attr(target_os = "none", reexport_test_harness_main = "test_main")]
+
+extern crate alloc;  // `alloc` is not implicitly linked in `no_std` crates
 
 pub mod vga_buffer;
 pub mod serial;
 pub mod interrupts;
+pub mod exceptions;
+pub mod apic;
 pub mod gdt;
 pub mod memory;
+pub mod acpi;
+pub mod power;
+pub mod time;
+pub mod task;
+pub mod thread;
+pub mod debug;
+pub mod allocator;
+pub mod testing;
+
+// global allocator has to be declared at crate root, heap memory is
+// handed over to it by `allocator::init_heap`; only registered for
+// our bare metal target as this crate is also linked to `std` test
+// binaries on host, which already provide an allocator
+#[cfg_attr(target_os = "none", global_allocator)]
+pub static ALLOCATOR: allocator::KernelHeap = allocator::KernelHeap::empty();
+
+// value written to `isa-debug-exit` device, QEMU exits with status
+// `(value << 1) | 1`, so success is 33 and failure is 35; 0 is avoided
+// as its status 1 is same as QEMU's own failure
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QemuExitCode {
+    Success,
+    Failed,
+    Custom(u32),
+}
 
-pub unsafe fn exit_qemu() {
+impl QemuExitCode {
+    pub fn value(self) -> u32 {
+        match self {
+            QemuExitCode::Success => 0x10,
+            QemuExitCode::Failed => 0x11,
+            QemuExitCode::Custom(value) => value,
+        }
+    }
+
+    // exit status of QEMU process for this code
+    pub fn exit_status(self) -> u32 {
+        (self.value() << 1) | 1
+    }
+}
+
+pub unsafe fn exit_qemu(exit_code: QemuExitCode) {
     use x86_64::instructions::port::Port;
 
-    // attach `isa-debug-exit` device on 0xf4 port while starting QEMU
-    let mut port = Port::<u8>::new(0xf4);
-    port.write(0);  // exit status will be `(passed value << 1) | 1` i.e. `1`
+    // attach `isa-debug-exit` device on 0xf4 port with 4 bytes size
+    // while starting QEMU
+    let mut port = Port::<u32>::new(0xf4);
+    port.write(exit_code.value());
 }
 
 pub fn hlt_loop() -> ! {
@@ -20,3 +77,67 @@ pub fn hlt_loop() -> ! {
         x86_64::instructions::hlt();
     }
 }
+
+// `sti` takes effect after next instruction, so an interrupt arriving
+// after checking for work with interrupts disabled still wakes `hlt`
+pub fn enable_interrupts_and_hlt() {
+    unsafe { asm!("sti; hlt" :::: "volatile") };
+}
+
+// called when global allocator returns null i.e. heap is exhausted or
+// requested layout cannot be satisfied
+#[cfg(target_os = "none")]
+#[alloc_error_handler]
+fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
+    println!("Allocation error: {:?}", layout);
+    serial_println!("Allocation error: {:?}", layout);
+
+    hlt_loop();
+}
+
+// entry point and panic handler of kernel built with `cargo xtest`,
+// everything tests may need is initialized before running them;
+// `entry_point!` can't be used as it's disabled for test builds
+#[cfg(all(test, target_os = "none"))]
+#[no_mangle]
+pub extern "C" fn _start(boot_info: &'static bootloader::BootInfo) -> ! {
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator = unsafe {
+        memory::init_frame_allocator(&boot_info.memory_map, boot_info.physical_memory_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    gdt::init();
+    interrupts::init_idt();
+
+    let apic_config = match acpi::init() {
+        Ok(_) => acpi::madt().and_then(|madt| madt.apic_config()).unwrap_or_default(),
+        Err(_) => apic::ApicConfig::default(),
+    };
+    time::init(time::DEFAULT_FREQUENCY).expect("claiming timer IRQ failed");
+    thread::init().expect("claiming timer IRQ failed");
+    apic::init(&apic_config).expect("APIC initialization failed");
+    testing::start_watchdog().expect("claiming timer IRQ failed");  // uses APIC for NMIs
+    x86_64::instructions::interrupts::enable();
+
+    test_main();
+    hlt_loop();  // runner exits QEMU, so this is never reached
+}
+
+#[cfg(all(test, target_os = "none"))]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    testing::panic_handler(info)
+}
+
+
+#[cfg(all(test, not(target_os = "none")))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn exit_status_is_shifted_value() {
+        assert_eq!(QemuExitCode::Success.exit_status(), 33);
+        assert_eq!(QemuExitCode::Failed.exit_status(), 35);
+        assert_eq!(QemuExitCode::Custom(3).exit_status(), 7);
+    }
+}
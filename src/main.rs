@@ -2,9 +2,13 @@
 #![cfg_attr(not(test), no_main)]  // don't call main as we need to define our own entry point
 #![cfg_attr(test, allow(unused_imports))]
 
+extern crate alloc;
+
 use core::panic::PanicInfo;
+use core::time::Duration;
+use alloc::{boxed::Box, vec, vec::Vec, rc::Rc};
 use bootloader::BootInfo;
-use x86_64::structures::paging::{MapperAllSizes, Page};
+use x86_64::structures::paging::{MapperAllSizes, Page, PageTableFlags, Size4KiB};
 use phil_opp_rust_os::*;
 
 // called on panic, required because std is not linked and it won't
@@ -13,7 +17,9 @@ use phil_opp_rust_os::*;
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     println!("{}", info);
+    serial_println!("{}", info);
 
+    unsafe { exit_qemu(QemuExitCode::Failed); }  // only if running in QEMU with exit device
     hlt_loop();
 }
 
@@ -33,10 +39,34 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     serial_print!("This is printed using {} macro\n", "serial_print");
     serial_println!("This is printed using {} macro", "serial_println");
 
-    gdt::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };  // create memory mapper
+    let mut frame_allocator = unsafe {
+        memory::init_frame_allocator(&boot_info.memory_map, boot_info.physical_memory_offset)
+    };
+
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    let apic_config = match acpi::init() {
+        Ok(_) => acpi::madt().and_then(|madt| madt.apic_config()).unwrap_or_default(),
+        Err(err) => {
+            println!("ACPI tables not found: {:?}", err);
+            apic::ApicConfig::default()
+        },
+    };
+
+    gdt::init();  // allocates IST stacks, so memory must be initialized first
     interrupts::init_idt();
-    unsafe { interrupts::PICS.lock().initialize(); }  // unsafe as misconfigured PIC can cause undefined behavior
+    interrupts::init_devices();  // registering handlers needs heap
+    time::init(time::DEFAULT_FREQUENCY).expect("claiming timer IRQ failed");
+    thread::init().expect("claiming timer IRQ failed");  // main continues as a thread
+    apic::init(&apic_config).expect("APIC initialization failed");  // masks PICs
     x86_64::instructions::interrupts::enable();  // executes `sti` instruction - set interrupts
+    time::calibrate_delay();  // needs timer interrupts
+    time::rtc::init();
+    println!("Booted at {}", time::rtc::now());
+    println!("Using {} clock", time::clock::select().name());
+    time::timer::every(Duration::from_secs(1), || print!("."));  // shows that timer interrupts arrive
 
     x86_64::instructions::interrupts::int3();
     println!("It did not crash on breakpoint!");
@@ -57,25 +87,54 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     //     for _ in 1..10000 {}
     // }
 
-    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };  // create memory mapper
-    let mut frame_allocator = memory::init_frame_allocator(&boot_info.memory_map);
 
     // map page with a random address to VGA buffer frame
-    let page = Page::containing_address(x86_64::VirtAddr::new(0xdeadbeef));
-    memory::create_example_mapping(page, &mut mapper, &mut frame_allocator);
+    let page: Page = Page::containing_address(x86_64::VirtAddr::new(0xdeadbeef));
+    let vga_buffer = memory::Backing::Physical(x86_64::PhysAddr::new(0xb8000));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let pages = Page::range(page, page + 1);
+    memory::map_range::<Size4KiB, _, _>(&mut mapper, pages, vga_buffer, flags, &mut frame_allocator)
+        .expect("mapping VGA buffer failed");  // page size can't be inferred as `mapper` supports all sizes
 
     // try writing to mapped page
     let page_ptr: *mut u64 = page.start_address().as_mut_ptr();
     unsafe { page_ptr.offset(400).write_volatile(0x_f021_f077_f065_f04e) };
 
-    // try converting these virtual addresses into physical
+    // try converting these virtual addresses into physical, last one
+    // might be mapped using huge pages by bootloader
     let addresses = [0xb8000, 0x20010a, boot_info.physical_memory_offset];
     for &address in &addresses {
         let virt = x86_64::VirtAddr::new(address);
         let phys = mapper.translate_addr(virt);
-        println!("{:?} -> {:?}", virt, phys);
+        let page_size = unsafe {
+            memory::translate_addr(virt, boot_info.physical_memory_offset)
+        }.map(|(_, size)| size);
+        println!("{:?} -> {:?} (page size: {:?})", virt, phys, page_size);
     }
 
-    println!("It did not crash!");
-    hlt_loop();
+    memory::with_frame_allocator(|allocator| {
+        println!("Frames: {} used, {} free of {} total", allocator.used_frames(),
+                 allocator.free_frames(), allocator.total_frames());
+    });
+
+    // try allocating on heap
+    let x = Box::new(41);
+    println!("heap value at {:p}", x);
+
+    let mut vec = Vec::new();
+    for i in 0..500 {
+        vec.push(i);
+    }
+    println!("vec at {:p}", vec.as_slice());
+
+    let rc = Rc::new(vec![1, 2, 3]);
+    let cloned_rc = rc.clone();
+    println!("current reference count is {}", Rc::strong_count(&cloned_rc));
+    core::mem::drop(rc);
+    println!("reference count is {} now", Rc::strong_count(&cloned_rc));
+
+    println!("It did not crash! Uptime: {:?}", time::uptime());
+    let mut executor = task::Executor::new();
+    executor.spawn(task::keyboard::print_keypresses());
+    executor.run();
 }
@@ -1,22 +1,58 @@
-use x86_64::registers::control::Cr3;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Mutex;
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr3};
+use x86_64::instructions::interrupts;
 use x86_64::{PhysAddr, VirtAddr};
+use x86_64::structures::idt::PageFaultErrorCode;
 use x86_64::structures::paging::{
     PageTable,
     page_table::FrameError,
     PhysFrame,
     MapperAllSizes,
     MappedPageTable,
-    Page,
+    PageSize,
     Size4KiB,
-    Mapper,
+    Size2MiB,
+    Size1GiB,
     FrameAllocator,
-    PageTableFlags
+    FrameDeallocator,
 };
-use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use bootloader::bootinfo::MemoryMap;
+
+pub use self::bitmap::BitmapFrameAllocator;
+pub use self::buddy::BuddyFrameAllocator;
+pub use self::address_space::AddressSpace;
+pub use self::mapping::{map_range, unmap_range, map_mmio, unmap_mmio, Backing, MappingError};
+
+mod bitmap;
+pub mod buddy;
+pub mod address_space;
+mod mapping;
+pub mod region;
+pub mod cow;
+pub mod stack;
+
+// set once by `init`, needed to access page tables and frames later on
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+static KERNEL_P4_FRAME: AtomicU64 = AtomicU64::new(0);  // level 4 table set up by bootloader
+
+// owned by kernel after `init_frame_allocator`, use `with_frame_allocator`
+// or `GlobalFrameAllocator` to access it
+static FRAME_ALLOCATOR: Mutex<Option<BuddyFrameAllocator>> = Mutex::new(None);
+
+// held while changing page tables, through `with_mapper` or by hand
+// with `with_page_tables`; kernel tables are shared by all threads and address spaces, so two threads could
+// otherwise both create a missing table and one mapping would be lost
+static PAGE_TABLES: Mutex<()> = Mutex::new(());
 
 // initialize a new MappedPageTable, a MapperAllSizes implementation
 // since return type is not concrete, it can easily switched to RecursivePageTable
 pub unsafe fn init(physical_memory_offset: u64) -> impl MapperAllSizes {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset, Ordering::Relaxed);
+    KERNEL_P4_FRAME.store(Cr3::read().0.start_address().as_u64(), Ordering::Relaxed);
+    Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));  // kernel writes to read-only pages fault too, needed for copy-on-write
+
     let l4_table = active_level4_table(physical_memory_offset);
     let phys_to_virt = move |frame: PhysFrame| -> *mut PageTable {
         let phys = frame.start_address().as_u64();
@@ -38,73 +74,145 @@ unsafe fn active_level4_table(physical_memory_offset: u64)
     &mut *page_table_ptr  // unsafe
 }
 
-// map given to frame containing VGA physical address
-pub fn create_example_mapping(
-    page: Page,
-    mapper: &mut impl Mapper<Size4KiB>,  // not using MapperAllSizes as we'll only call it for 4KiB pages
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) {
-    let frame = PhysFrame::containing_address(PhysAddr::new(0xb8000));
-    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+// create frame allocator from memory map provided by bootloader, unsafe
+// as caller must guarantee that complete physical memory is mapped at
+// given offset and usable regions in memory map are really unused;
+// buddy allocator is used as it can also serve contiguous frames
+pub unsafe fn init_frame_allocator(
+    memory_map: &'static MemoryMap,
+    physical_memory_offset: u64,
+) -> GlobalFrameAllocator {
+    let allocator = BuddyFrameAllocator::init(memory_map, physical_memory_offset);
+    interrupts::without_interrupts(|| {
+        *FRAME_ALLOCATOR.lock() = Some(allocator);
+    });
+    GlobalFrameAllocator
+}
 
-    let map_to_result = unsafe {
-        mapper.map_to(page, frame, flags, frame_allocator)
-    };
-    map_to_result.expect("map_to failed").flush();  // is page is mapped, flush it from TLB
+// run given closure with exclusive access to kernel frame allocator,
+// interrupts are disabled meanwhile so handlers can allocate frames too
+pub fn with_frame_allocator<F, R>(f: F) -> R
+    where F: FnOnce(&mut BuddyFrameAllocator) -> R
+{
+    interrupts::without_interrupts(|| {
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        f(allocator.as_mut().expect("frame allocator is not initialized"))
+    })
+}
+
+// handle to kernel frame allocator which can be passed wherever a frame
+// allocator is needed, it locks the allocator only for each allocation
+#[derive(Debug, Clone, Copy)]
+pub struct GlobalFrameAllocator;
+
+impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        with_frame_allocator(|allocator| allocator.allocate_frame())
+    }
 }
 
-// Frame allocator for creating intermediate level page tables while creating new mapping
-pub struct BootInfoFrameAllocator<I> where I: Iterator<Item = PhysFrame> {
-    frames: I,
+impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
+    fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        with_frame_allocator(|allocator| allocator.deallocate_frame(frame))
+    }
 }
 
-impl<I> FrameAllocator<Size4KiB> for BootInfoFrameAllocator<I>
-    where I: Iterator<Item = PhysFrame>
-{
-    fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        self.frames.next()
+impl FrameAllocator<Size2MiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        with_frame_allocator(|allocator| allocator.allocate_frame())
     }
 }
 
-// create frames from memory map provided by bootloader to initialize frame allocator
-pub fn init_frame_allocator(
-    memory_map: &'static MemoryMap
-) -> BootInfoFrameAllocator<impl Iterator<Item = PhysFrame>> {
-    // get usabel memory region ranges and extract 4KiB frame addresses
-    let regions = memory_map.iter().filter(|r| {
-        r.region_type == MemoryRegionType::Usable
-    });
-    let addr_ranges = regions.map(|r| {
-        r.range.start_addr()..r.range.end_addr()
-    });
-    let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
+impl FrameDeallocator<Size2MiB> for GlobalFrameAllocator {
+    fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        with_frame_allocator(|allocator| allocator.deallocate_frame(frame))
+    }
+}
 
-    // create physical frames from addresses
-    let frames = frame_addresses.map(|addr| {
-        PhysFrame::containing_address(PhysAddr::new(addr))
-    });
+// called by page fault handler, returns true if fault is resolved by
+// copying a copy-on-write page or populating a lazy region
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    cow::handle_page_fault(addr, error_code) || region::handle_page_fault(addr, error_code)
+}
 
-    BootInfoFrameAllocator{ frames }
+pub fn physical_memory_offset() -> u64 {
+    PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed)
 }
 
-// this function is not used in favour of `translate_addr` provided by
-// MapperAllSizes returned from above `init` function
-pub unsafe fn translate_addr(addr: VirtAddr, physical_memory_offset: u64)
-                             -> Option<PhysAddr>
+// virtual address through which given physical address can be accessed
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(addr.as_u64() + physical_memory_offset())
+}
+
+// can be used as `PhysToVirt` for `MappedPageTable` once `init` is called
+pub fn frame_to_page_table(frame: PhysFrame) -> *mut PageTable {
+    phys_to_virt(frame.start_address()).as_mut_ptr()
+}
+
+type KernelMapper = MappedPageTable<'static, address_space::PhysToVirt>;
+
+// run given closure with mapper for level 4 table in given frame while
+// holding page table lock with interrupts disabled, as page fault
+// handler maps pages too
+fn with_mapper<F, R>(p4_frame: PhysFrame, f: F) -> R
+    where F: FnOnce(&mut KernelMapper) -> R
 {
-    translate_addr_internal(addr, physical_memory_offset)  // all logic is separated to limit unsafe code block
+    with_page_tables(|| f(&mut mapper_for(p4_frame)))
 }
 
-fn translate_addr_internal(addr: VirtAddr, physical_memory_offset: u64)
-                             -> Option<PhysAddr>
+// same for page fault handler, which gives up instead of deadlocking
+// if the fault happened while changing page tables
+fn try_with_mapper<F, R>(p4_frame: PhysFrame, f: F) -> Option<R>
+    where F: FnOnce(&mut KernelMapper) -> R
+{
+    try_with_page_tables(|| f(&mut mapper_for(p4_frame)))
+}
+
+// run given closure holding page table lock, for walking and changing
+// tables without a mapper
+fn with_page_tables<F: FnOnce() -> R, R>(f: F) -> R {
+    interrupts::without_interrupts(|| {
+        let _tables = PAGE_TABLES.lock();
+        f()
+    })
+}
+
+fn try_with_page_tables<F: FnOnce() -> R, R>(f: F) -> Option<R> {
+    let _tables = PAGE_TABLES.try_lock()?;
+    Some(f())
+}
+
+// mapper for level 4 table in given frame, callers must not keep
+// it around as it aliases the tables
+fn mapper_for(p4_frame: PhysFrame) -> KernelMapper {
+    let p4 = unsafe { &mut *frame_to_page_table(p4_frame) };
+    unsafe { MappedPageTable::new(p4, frame_to_page_table as address_space::PhysToVirt) }
+}
+
+pub fn kernel_p4_frame() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(KERNEL_P4_FRAME.load(Ordering::Relaxed)))
+}
+
+// unlike `translate_addr` provided by MapperAllSizes returned from above
+// `init` function, this also returns size of the page (in bytes) that
+// maps given address
+pub unsafe fn translate_addr(addr: VirtAddr, physical_memory_offset: u64)
+                             -> Option<(PhysAddr, u64)>
 {
     let (l4_table_frame, _) = Cr3::read();
+    translate_addr_internal(addr, l4_table_frame, physical_memory_offset)  // all logic is separated to limit unsafe code block
+}
+
+// walk page tables starting from given level 4 table
+fn translate_addr_internal(addr: VirtAddr, l4_table_frame: PhysFrame, physical_memory_offset: u64)
+                             -> Option<(PhysAddr, u64)>
+{
     let table_indices = [
         addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()
     ];
 
     let mut frame = l4_table_frame;
-    for &index in &table_indices {
+    for (level, &index) in table_indices.iter().enumerate() {
         let virt = VirtAddr::new(frame.start_address().as_u64() + physical_memory_offset);
         let table_ptr: *const PageTable = virt.as_ptr();
         let table = unsafe { &*table_ptr };
@@ -113,9 +221,38 @@ fn translate_addr_internal(addr: VirtAddr, physical_memory_offset: u64)
         frame = match entry.frame() {
             Ok(frame) => frame,
             Err(FrameError::FrameNotPresent) => return None,
-            Err(FrameError::HugeFrame) => panic!("huge pages not supported"),
+            // huge page entry points directly to the mapped frame, so
+            // rest of the address bits are offset into that frame
+            Err(FrameError::HugeFrame) => match level {
+                1 => return Some(huge_page_addr(entry.addr(), addr, Size1GiB::SIZE)),
+                2 => return Some(huge_page_addr(entry.addr(), addr, Size2MiB::SIZE)),
+                3 => PhysFrame::containing_address(entry.addr()),  // same bit means PAT in level 1 entries
+                _ => return None,  // level 4 entries can't map huge pages
+            },
         };
     }
 
-    Some(frame.start_address() + u64::from(addr.page_offset()))
+    Some((frame.start_address() + u64::from(addr.page_offset()), Size4KiB::SIZE))
+}
+
+// bit 12 of huge page entries is PAT, so it's cleared from address
+fn huge_page_addr(frame_addr: PhysAddr, addr: VirtAddr, page_size: u64) -> (PhysAddr, u64) {
+    let offset = addr.as_u64() & (page_size - 1);
+    (frame_addr.align_down(page_size) + offset, page_size)
+}
+
+
+#[cfg(all(test, not(target_os = "none")))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn huge_page_addr_ignores_pat_bit() {
+        let pat = 1 << 12;
+        let addr = VirtAddr::new(0x4020_1234);
+        assert_eq!(huge_page_addr(PhysAddr::new(0x20_0000 | pat), addr, Size2MiB::SIZE),
+                   (PhysAddr::new(0x20_1234), Size2MiB::SIZE));
+        assert_eq!(huge_page_addr(PhysAddr::new(0x4000_0000 | pat), addr, Size1GiB::SIZE),
+                   (PhysAddr::new(0x4020_1234), Size1GiB::SIZE));
+    }
 }
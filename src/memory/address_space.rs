@@ -0,0 +1,292 @@
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::registers::control::{Cr3, Cr3Flags};
+use x86_64::instructions::tlb;
+use x86_64::structures::paging::{
+    PageTable,
+    page_table::PageTableEntry,
+    PhysFrame,
+    MappedPageTable,
+    Page,
+    PageSize,
+    Mapper,
+    FrameAllocator,
+    FrameDeallocator,
+    PageTableFlags,
+    mapper::{MapToError, UnmapError, MapperFlush},
+};
+
+use super::cow;
+use super::mapping::MappingError;
+use super::{
+    GlobalFrameAllocator,
+    frame_to_page_table,
+    kernel_p4_frame,
+    physical_memory_offset,
+    with_mapper,
+    with_page_tables,
+};
+
+pub(super) type PhysToVirt = fn(PhysFrame) -> *mut PageTable;
+
+// Page table hierarchy with its own level 4 table. Level 4 entries used
+// by kernel table at the time of creation are copied, so kernel code,
+// stack, heap and physical memory mapping (these are spread over both
+// halves by bootloader) stay accessible after switching to it, user
+// pages can't be mapped below them. Tables below rest of the entries
+// are private and freed on drop.
+#[derive(Debug)]
+pub struct AddressSpace {
+    p4_frame: PhysFrame,
+}
+
+impl AddressSpace {
+    pub fn new() -> Result<Self, MapToError> {
+        let p4_frame = GlobalFrameAllocator.allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+
+        let p4 = unsafe { &mut *frame_to_page_table(p4_frame) };
+        let kernel_p4 = unsafe { &*frame_to_page_table(kernel_p4_frame()) };
+        p4.zero();
+        for (entry, kernel_entry) in p4.iter_mut().zip(kernel_p4.iter()) {
+            if !kernel_entry.is_unused() {
+                *entry = kernel_entry.clone();
+            }
+        }
+
+        Ok(AddressSpace { p4_frame })
+    }
+
+    pub fn p4_frame(&self) -> PhysFrame {
+        self.p4_frame
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.p4_frame
+    }
+
+    // unsafe as caller must switch to another address space before
+    // dropping this one
+    pub unsafe fn switch(&self) {
+        Cr3::write(self.p4_frame, Cr3Flags::empty());
+    }
+
+    // map given page in this address space, parent table entries are
+    // made user accessible too if requested in `flags`; unsafe as
+    // `frame` must not be used for anything else
+    pub unsafe fn map<S: PageSize>(
+        &mut self,
+        page: Page<S>,
+        frame: PhysFrame<S>,
+        flags: PageTableFlags,
+    ) -> Result<(), MappingError>
+        where for<'a> MappedPageTable<'a, PhysToVirt>: Mapper<S>
+    {
+        check_user_page(self.p4_frame, page, flags)?;
+        let (p4_frame, active) = (self.p4_frame, self.is_active());
+        with_mapper(p4_frame, |mapper| {
+            let flush = mapper.map_to(page, frame, flags, &mut GlobalFrameAllocator)?;
+            if flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+                set_parents_user_accessible(p4_frame, page);
+            }
+            flush_if(flush, active);
+            Ok(())
+        })
+    }
+
+    // remove mapping of given page and return the frame it was mapped
+    // to, frame is not deallocated; if page was shared copy-on-write,
+    // caller must drop its reference with `cow::release`
+    pub fn unmap<S: PageSize>(&mut self, page: Page<S>) -> Result<PhysFrame<S>, UnmapError>
+        where for<'a> MappedPageTable<'a, PhysToVirt>: Mapper<S>
+    {
+        let active = self.is_active();
+        with_mapper(self.p4_frame, |mapper| {
+            let (frame, flush) = mapper.unmap(page)?;
+            flush_if(flush, active);
+            Ok(frame)
+        })
+    }
+
+    // change flags of an already mapped page
+    pub fn protect<S: PageSize>(
+        &mut self,
+        page: Page<S>,
+        flags: PageTableFlags,
+    ) -> Result<(), MappingError>
+        where for<'a> MappedPageTable<'a, PhysToVirt>: Mapper<S>
+    {
+        check_user_page(self.p4_frame, page, flags)?;
+        let (p4_frame, active) = (self.p4_frame, self.is_active());
+        with_mapper(p4_frame, |mapper| {
+            let flush = mapper.update_flags(page, flags)?;
+            if flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+                set_parents_user_accessible(p4_frame, page);
+            }
+            flush_if(flush, active);
+            Ok(())
+        })
+    }
+
+    // create a copy of this address space in which all pages owned by
+    // it are shared copy-on-write, they're made read-only and marked in
+    // both spaces, so first write to such a page in either of them gives
+    // it a private copy; pages not owned, e.g. MMIO, are mapped the same
+    // in both; page tables are not shared
+    pub fn clone_cow(&mut self) -> Result<AddressSpace, MapToError> {
+        let child = AddressSpace::new()?;  // has kernel entries already
+
+        let p4 = unsafe { &mut *frame_to_page_table(self.p4_frame) };
+        let child_p4 = unsafe { &mut *frame_to_page_table(child.p4_frame) };
+        let kernel_p4 = unsafe { &*frame_to_page_table(kernel_p4_frame()) };
+        let copied = with_page_tables(|| {
+            for (i, entry) in p4.iter_mut().enumerate() {
+                if entry.is_unused() || is_shared(entry, &kernel_p4[i]) {
+                    continue;
+                }
+                copy_table(entry, &mut child_p4[i], 3)?;
+            }
+            Ok(())
+        });
+        if self.is_active() {
+            tlb::flush_all();  // pages were made read-only
+        }
+
+        // on error, tables copied so far are reachable from child and
+        // freed when it's dropped
+        copied.map(|()| child)
+    }
+
+    // physical address and page size given address is mapped to
+    pub fn translate_addr(&self, addr: VirtAddr) -> Option<(PhysAddr, u64)> {
+        super::translate_addr_internal(addr, self.p4_frame, physical_memory_offset())
+    }
+
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "dropping active address space");
+
+        let p4 = unsafe { &*frame_to_page_table(self.p4_frame) };
+        let kernel_p4 = unsafe { &*frame_to_page_table(kernel_p4_frame()) };
+        for (entry, kernel_entry) in p4.iter().zip(kernel_p4.iter()) {
+            if is_shared(entry, kernel_entry) {
+                continue;  // kernel tables must stay
+            }
+            if let Ok(frame) = entry.frame() {
+                free_table(frame, 3);
+            }
+        }
+        GlobalFrameAllocator.deallocate_frame(self.p4_frame);
+    }
+}
+
+// switch back to level 4 table set up by bootloader
+pub unsafe fn switch_to_kernel() {
+    Cr3::write(kernel_p4_frame(), Cr3Flags::empty());
+}
+
+fn is_shared(entry: &PageTableEntry, kernel_entry: &PageTableEntry) -> bool {
+    !kernel_entry.is_unused() && entry.addr() == kernel_entry.addr()
+}
+
+// user pages in tables shared with kernel would show up in every
+// address space, and user bit would be set on kernel tables; kernel
+// table itself may map user pages anywhere
+pub(super) fn check_user_page<S: PageSize>(
+    p4_frame: PhysFrame,
+    page: Page<S>,
+    flags: PageTableFlags,
+) -> Result<(), MappingError> {
+    if !flags.contains(PageTableFlags::USER_ACCESSIBLE) || p4_frame == kernel_p4_frame() {
+        return Ok(());
+    }
+    let index = page.start_address().p4_index();
+    let p4 = unsafe { &*frame_to_page_table(p4_frame) };
+    let kernel_p4 = unsafe { &*frame_to_page_table(kernel_p4_frame()) };
+    if is_shared(&p4[index], &kernel_p4[index]) {
+        Err(MappingError::SharedWithKernel)
+    } else {
+        Ok(())
+    }
+}
+
+// free given table of given level with all tables below it, mapped
+// frames are freed only if they are shared copy-on-write or owned by
+// the address space, rest of them belong to whoever mapped them
+fn free_table(frame: PhysFrame, level: usize) {
+    let table = unsafe { &*frame_to_page_table(frame) };
+    for entry in table.iter().filter(|entry| !entry.is_unused()) {
+        match entry.frame() {
+            Ok(next) if level > 1 => free_table(next, level - 1),
+            _ => cow::release_entry(entry, level),  // mapped page
+        }
+    }
+    GlobalFrameAllocator.deallocate_frame(frame);
+}
+
+// copy table referenced by `entry` of given level into a new table
+// referenced by `child_entry`, pages mapped by it are shared between
+// both copies and are marked copy-on-write if the space owns them
+fn copy_table(
+    entry: &PageTableEntry,
+    child_entry: &mut PageTableEntry,
+    level: usize,
+) -> Result<(), MapToError> {
+    let child_frame = GlobalFrameAllocator.allocate_frame()
+        .ok_or(MapToError::FrameAllocationFailed)?;
+    let child_table = unsafe { &mut *frame_to_page_table(child_frame) };
+    child_table.zero();
+    child_entry.set_frame(child_frame, entry.flags());
+
+    let table = unsafe { &mut *frame_to_page_table(entry.frame().expect("not a table entry")) };
+    for (entry, child_entry) in table.iter_mut().zip(child_table.iter_mut()) {
+        if entry.is_unused() {
+            continue;
+        }
+        let is_table = level > 1 && !entry.flags().contains(PageTableFlags::HUGE_PAGE);
+        if is_table {
+            copy_table(entry, child_entry, level - 1)?;
+            continue;
+        }
+
+        let mut flags = entry.flags();
+        if flags.intersects(cow::COW | cow::OWNED) {
+            flags = (flags - PageTableFlags::WRITABLE - cow::OWNED) | cow::COW;
+            entry.set_flags(flags);
+            cow::share(entry.addr());
+        }
+        child_entry.set_addr(entry.addr(), flags);
+    }
+
+    Ok(())
+}
+
+// intermediate tables are created only present and writable by
+// `MappedPageTable`, CPU checks user bit at every level
+pub(super) fn set_parents_user_accessible<S: PageSize>(p4_frame: PhysFrame, page: Page<S>) {
+    let addr = page.start_address();
+    let indices = [addr.p4_index(), addr.p3_index(), addr.p2_index()];
+    let parent_levels = match S::SIZE {
+        0x1000 => 3,
+        0x20_0000 => 2,
+        _ => 1,
+    };
+
+    let mut table = unsafe { &mut *frame_to_page_table(p4_frame) };
+    for &index in indices.iter().take(parent_levels) {
+        let entry = &mut table[index];
+        entry.set_flags(entry.flags() | PageTableFlags::USER_ACCESSIBLE);
+        let next = entry.frame().expect("parent table of mapped page is missing");
+        table = unsafe { &mut *frame_to_page_table(next) };
+    }
+}
+
+// TLB only needs flushing if modified table is in use
+fn flush_if<S: PageSize>(flush: MapperFlush<S>, active: bool) {
+    if active {
+        flush.flush();
+    } else {
+        flush.ignore();
+    }
+}
@@ -0,0 +1,202 @@
+use core::slice;
+
+use x86_64::PhysAddr;
+use x86_64::structures::paging::{PhysFrame, Size4KiB, FrameAllocator, FrameDeallocator};
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+
+const FRAME_SIZE: u64 = 4096;
+const BITS_PER_WORD: usize = 64;
+
+// Physical frame allocator which keeps one bit per 4KiB frame, set
+// bit means frame is in use. Bitmap itself is stored in the first
+// usable region big enough to hold it, so no heap is needed and it
+// can be used to map the heap itself.
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    total_frames: usize,  // usable frames, excluding the ones bitmap is stored in
+    used_frames: usize,
+    next_word: usize,  // search hint, all words before this are known to be full
+}
+
+impl BitmapFrameAllocator {
+    // unsafe as caller must guarantee that complete physical memory is
+    // mapped at `physical_memory_offset` and all frames marked as
+    // `Usable` in memory map are really unused
+    pub unsafe fn init(memory_map: &MemoryMap, physical_memory_offset: u64) -> Self {
+        let usable_regions = || memory_map.iter().filter(|r| {
+            r.region_type == MemoryRegionType::Usable
+        });
+
+        let frame_count = usable_regions()
+            .map(|r| r.range.end_frame_number)
+            .max()
+            .unwrap_or(0) as usize;
+        let word_count = (frame_count + BITS_PER_WORD - 1) / BITS_PER_WORD;
+        let bitmap_frames = ((word_count * 8) as u64 + FRAME_SIZE - 1) / FRAME_SIZE;
+
+        let bitmap_region = usable_regions()
+            .find(|r| r.range.end_frame_number - r.range.start_frame_number >= bitmap_frames)
+            .expect("no usable region is big enough for frame bitmap");
+        let bitmap_start = bitmap_region.range.start_frame_number;
+
+        let bitmap_ptr = (bitmap_region.range.start_addr() + physical_memory_offset) as *mut u64;
+        let bitmap = slice::from_raw_parts_mut(bitmap_ptr, word_count);
+
+        // everything is used unless memory map says otherwise
+        for word in bitmap.iter_mut() {
+            *word = !0;
+        }
+        let mut allocator = BitmapFrameAllocator {
+            bitmap,
+            total_frames: 0,
+            used_frames: 0,
+            next_word: 0,
+        };
+        for region in usable_regions() {
+            for frame in region.range.start_frame_number..region.range.end_frame_number {
+                let is_bitmap_frame = frame >= bitmap_start && frame < bitmap_start + bitmap_frames;
+                if !is_bitmap_frame {
+                    allocator.clear_bit(frame as usize);
+                    allocator.total_frames += 1;
+                }
+            }
+        }
+
+        allocator
+    }
+
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+
+    pub fn used_frames(&self) -> usize {
+        self.used_frames
+    }
+
+    pub fn free_frames(&self) -> usize {
+        self.total_frames - self.used_frames
+    }
+
+    fn is_set(&self, index: usize) -> bool {
+        self.bitmap[index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0
+    }
+
+    fn set_bit(&mut self, index: usize) {
+        self.bitmap[index / BITS_PER_WORD] |= 1 << (index % BITS_PER_WORD);
+    }
+
+    fn clear_bit(&mut self, index: usize) {
+        self.bitmap[index / BITS_PER_WORD] &= !(1 << (index % BITS_PER_WORD));
+    }
+}
+
+impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let word_index = (self.next_word..self.bitmap.len())
+            .find(|&i| self.bitmap[i] != !0)?;
+        self.next_word = word_index;
+
+        let bit = (!self.bitmap[word_index]).trailing_zeros() as usize;  // first unset bit
+        let index = word_index * BITS_PER_WORD + bit;
+        self.set_bit(index);
+        self.used_frames += 1;
+
+        Some(PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE)))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let index = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+        assert!(self.is_set(index), "deallocating unused frame {:?}", frame);
+
+        self.clear_bit(index);
+        self.used_frames -= 1;
+        if index / BITS_PER_WORD < self.next_word {
+            self.next_word = index / BITS_PER_WORD;
+        }
+    }
+}
+
+
+#[cfg(all(test, not(target_os = "none")))]
+mod test {
+    use super::*;
+    use bootloader::bootinfo::{MemoryRegion, FrameRange};
+
+    #[test]
+    fn counts_usable_frames() {
+        let (allocator, _memory) = construct_allocator();
+
+        // 1 of 256 frames in first region is used for bitmap
+        assert_eq!(allocator.total_frames(), 255 + 16);
+        assert_eq!(allocator.used_frames(), 0);
+        assert_eq!(allocator.free_frames(), 255 + 16);
+    }
+
+    #[test]
+    fn allocates_only_usable_frames() {
+        let (mut allocator, _memory) = construct_allocator();
+
+        let mut count = 0;
+        while let Some(frame) = allocator.allocate_frame() {
+            let addr = frame.start_address().as_u64();
+            let in_first = addr > 0x1000 && addr < 0x10_1000;  // first frame of region holds bitmap
+            let in_second = addr >= 0x20_0000 && addr < 0x21_0000;
+            assert!(in_first || in_second, "allocated unusable frame {:#x}", addr);
+            count += 1;
+        }
+        assert_eq!(count, 255 + 16);
+        assert_eq!(allocator.free_frames(), 0);
+    }
+
+    #[test]
+    fn reuses_deallocated_frames() {
+        let (mut allocator, _memory) = construct_allocator();
+
+        let first = allocator.allocate_frame().unwrap();
+        let second = allocator.allocate_frame().unwrap();
+        assert_ne!(first, second);
+        assert_eq!(allocator.used_frames(), 2);
+
+        allocator.deallocate_frame(first);
+        assert_eq!(allocator.used_frames(), 1);
+        assert_eq!(allocator.allocate_frame(), Some(first));
+    }
+
+    #[test]
+    #[should_panic]
+    fn double_free_panics() {
+        let (mut allocator, _memory) = construct_allocator();
+
+        let frame = allocator.allocate_frame().unwrap();
+        allocator.deallocate_frame(frame);
+        allocator.deallocate_frame(frame);
+    }
+
+    // builds an allocator for two usable regions with a hole in between,
+    // returned buffer acts as physical memory for the bitmap and must
+    // outlive the allocator
+    fn construct_allocator() -> (BitmapFrameAllocator, Box<[u64]>) {
+        let mut memory_map = MemoryMap::new();
+        memory_map.add_region(MemoryRegion {
+            range: FrameRange::new(0x1000, 0x10_1000),
+            region_type: MemoryRegionType::Usable,
+        });
+        memory_map.add_region(MemoryRegion {
+            range: FrameRange::new(0x10_1000, 0x20_0000),
+            region_type: MemoryRegionType::Reserved,
+        });
+        memory_map.add_region(MemoryRegion {
+            range: FrameRange::new(0x20_0000, 0x21_0000),
+            region_type: MemoryRegionType::Usable,
+        });
+
+        // bitmap will be placed at start of first usable region
+        let mut memory = vec![0u64; 512].into_boxed_slice();
+        let physical_memory_offset = memory.as_mut_ptr() as u64 - 0x1000;
+        let allocator = unsafe { BitmapFrameAllocator::init(&memory_map, physical_memory_offset) };
+
+        (allocator, memory)
+    }
+}
@@ -0,0 +1,315 @@
+use core::slice;
+
+use x86_64::PhysAddr;
+use x86_64::structures::paging::{
+    PhysFrame,
+    Size4KiB,
+    Size2MiB,
+    FrameAllocator,
+    FrameDeallocator,
+};
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+
+const FRAME_SIZE: u64 = 4096;
+const NONE: u64 = u64::max_value();  // marks end of a free list
+const FREE: u8 = 0x80;  // set in block state of first frame of every free block
+
+pub const MAX_ORDER: usize = 10;  // largest block is 2^10 frames i.e. 4MiB
+pub const ORDER_2MIB: usize = 9;
+
+// links of a free block, stored inside the free block itself
+struct FreeBlock {
+    next: u64,
+    prev: u64,
+}
+
+// Physical frame allocator which hands out naturally aligned blocks of
+// 2^order contiguous frames. Free blocks of each order are kept in a
+// doubly linked list stored in the blocks themselves and one byte per
+// frame tells if a frame starts a free block of a certain order, so a
+// freed block can be merged with its buddy if the buddy is free too.
+pub struct BuddyFrameAllocator {
+    free_lists: [u64; MAX_ORDER + 1],  // frame number of first free block of each order
+    block_state: &'static mut [u8],
+    physical_memory_offset: u64,
+    total_frames: usize,
+    free_frames: usize,
+}
+
+impl BuddyFrameAllocator {
+    // unsafe as caller must guarantee that complete physical memory is
+    // mapped at `physical_memory_offset` and all frames marked as
+    // `Usable` in memory map are really unused
+    pub unsafe fn init(memory_map: &MemoryMap, physical_memory_offset: u64) -> Self {
+        let usable_regions = || memory_map.iter().filter(|r| {
+            r.region_type == MemoryRegionType::Usable
+        });
+
+        let frame_count = usable_regions()
+            .map(|r| r.range.end_frame_number)
+            .max()
+            .unwrap_or(0);
+        let state_frames = (frame_count + FRAME_SIZE - 1) / FRAME_SIZE;
+
+        let state_region = usable_regions()
+            .find(|r| r.range.end_frame_number - r.range.start_frame_number >= state_frames)
+            .expect("no usable region is big enough for buddy allocator state");
+        let state_start = state_region.range.start_frame_number;
+
+        let state_ptr = (state_region.range.start_addr() + physical_memory_offset) as *mut u8;
+        let block_state = slice::from_raw_parts_mut(state_ptr, frame_count as usize);
+        for state in block_state.iter_mut() {
+            *state = 0;
+        }
+
+        let mut allocator = BuddyFrameAllocator {
+            free_lists: [NONE; MAX_ORDER + 1],
+            block_state,
+            physical_memory_offset,
+            total_frames: 0,
+            free_frames: 0,
+        };
+        for region in usable_regions() {
+            let mut start = region.range.start_frame_number;
+            if start == state_start {
+                start += state_frames;  // skip frames holding block state
+            }
+            allocator.add_frames(start, region.range.end_frame_number);
+        }
+
+        allocator
+    }
+
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+
+    pub fn used_frames(&self) -> usize {
+        self.total_frames - self.free_frames
+    }
+
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    // smallest order whose blocks can hold `size` bytes
+    pub fn order_for_size(size: u64) -> usize {
+        let frames = (size + FRAME_SIZE - 1) / FRAME_SIZE;
+        (0..=MAX_ORDER).find(|&order| 1 << order >= frames).unwrap_or(MAX_ORDER + 1)
+    }
+
+    // allocate 2^order physically contiguous frames, start address is
+    // aligned to the size of the block
+    pub fn allocate(&mut self, order: usize) -> Option<PhysAddr> {
+        let mut current = (order..=MAX_ORDER).find(|&o| self.free_lists[o] != NONE)?;
+        let block = self.free_lists[current];
+        self.remove(block, current);
+
+        // split larger block, putting upper halves back in free lists
+        while current > order {
+            current -= 1;
+            self.push(block + (1 << current), current);
+        }
+
+        self.free_frames -= 1 << order;
+        Some(PhysAddr::new(block * FRAME_SIZE))
+    }
+
+    // free block allocated with `allocate` for same order and merge it
+    // with its buddy as long as the buddy is free too
+    pub fn deallocate(&mut self, addr: PhysAddr, order: usize) {
+        let mut block = addr.as_u64() / FRAME_SIZE;
+        assert!(block % (1 << order) == 0, "{:?} is not aligned to order {}", addr, order);
+        assert!(!self.overlaps_free(block, order), "deallocating free block {:?}", addr);
+
+        self.free_frames += 1 << order;
+        let mut order = order;
+        while order < MAX_ORDER {
+            let buddy = block ^ (1 << order);
+            let buddy_free = (buddy as usize) < self.block_state.len()
+                && self.block_state[buddy as usize] == FREE | order as u8;
+            if !buddy_free {
+                break;
+            }
+            self.remove(buddy, order);
+            block = block.min(buddy);
+            order += 1;
+        }
+        self.push(block, order);
+    }
+
+    // whether any frame of block is part of a free block, either one
+    // inside it or a bigger one it was merged into
+    fn overlaps_free(&self, block: u64, order: usize) -> bool {
+        let state = |frame: u64| self.block_state.get(frame as usize).cloned().unwrap_or(0);
+        let inside = (block..block + (1 << order)).any(|frame| state(frame) & FREE != 0);
+        let containing = (order + 1..=MAX_ORDER).any(|o| {
+            state(block & !((1 << o) - 1)) == FREE | o as u8
+        });
+        inside || containing
+    }
+
+    // add frames in `start..end` to free lists as largest possible blocks
+    fn add_frames(&mut self, mut start: u64, end: u64) {
+        while start < end {
+            let mut order = MAX_ORDER;
+            while start % (1 << order) != 0 || start + (1 << order) > end {
+                order -= 1;
+            }
+            self.push(start, order);
+            self.total_frames += 1 << order;
+            self.free_frames += 1 << order;
+            start += 1 << order;
+        }
+    }
+
+    fn node(&mut self, block: u64) -> &mut FreeBlock {
+        let virt = block * FRAME_SIZE + self.physical_memory_offset;
+        unsafe { &mut *(virt as *mut FreeBlock) }  // unsafe but block is free, so nobody else uses it
+    }
+
+    fn push(&mut self, block: u64, order: usize) {
+        let head = self.free_lists[order];
+        *self.node(block) = FreeBlock { next: head, prev: NONE };
+        if head != NONE {
+            self.node(head).prev = block;
+        }
+        self.free_lists[order] = block;
+        self.block_state[block as usize] = FREE | order as u8;
+    }
+
+    fn remove(&mut self, block: u64, order: usize) {
+        let (next, prev) = {
+            let node = self.node(block);
+            (node.next, node.prev)
+        };
+        if prev == NONE {
+            self.free_lists[order] = next;
+        } else {
+            self.node(prev).next = next;
+        }
+        if next != NONE {
+            self.node(next).prev = prev;
+        }
+        self.block_state[block as usize] = 0;
+    }
+}
+
+impl FrameAllocator<Size4KiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        self.allocate(0).map(PhysFrame::containing_address)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BuddyFrameAllocator {
+    fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        self.deallocate(frame.start_address(), 0);
+    }
+}
+
+impl FrameAllocator<Size2MiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        self.allocate(ORDER_2MIB).map(PhysFrame::containing_address)
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BuddyFrameAllocator {
+    fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        self.deallocate(frame.start_address(), ORDER_2MIB);
+    }
+}
+
+
+#[cfg(all(test, not(target_os = "none")))]
+mod test {
+    use super::*;
+    use bootloader::bootinfo::{MemoryRegion, FrameRange};
+
+    const MEMORY_SIZE: u64 = 8 * 1024 * 1024;
+
+    #[test]
+    fn seeds_largest_aligned_blocks() {
+        let (allocator, _memory) = construct_allocator();
+
+        // frame 0 holds block state, rest is split as 1 + 2 + 4 + ... + 1024
+        assert_eq!(allocator.total_frames(), 2047);
+        assert_eq!(allocator.free_frames(), 2047);
+        for order in 0..=MAX_ORDER {
+            assert_eq!(allocator.free_lists[order], 1 << order);
+        }
+    }
+
+    #[test]
+    fn allocates_aligned_blocks() {
+        let (mut allocator, _memory) = construct_allocator();
+
+        let huge: PhysFrame<Size2MiB> = allocator.allocate_frame().unwrap();
+        assert_eq!(huge.start_address().as_u64(), 0x20_0000);
+        assert_eq!(allocator.used_frames(), 512);
+
+        let block = allocator.allocate(3).unwrap();
+        assert!(block.is_aligned(8 * FRAME_SIZE));
+        assert_eq!(allocator.used_frames(), 520);
+    }
+
+    #[test]
+    fn splits_and_coalesces() {
+        let (mut allocator, _memory) = construct_allocator();
+
+        // use up everything but the 4MiB block, so next frames come from splitting it
+        for order in 0..MAX_ORDER {
+            allocator.allocate(order).unwrap();
+        }
+        let frames: Vec<PhysFrame> = (0..16).map(|_| allocator.allocate_frame().unwrap()).collect();
+        assert!(allocator.allocate(MAX_ORDER).is_none());
+
+        for frame in frames {
+            allocator.deallocate_frame(frame);
+        }
+        assert_eq!(allocator.allocate(MAX_ORDER), Some(PhysAddr::new(0x40_0000)));
+    }
+
+    #[test]
+    fn order_for_size() {
+        assert_eq!(BuddyFrameAllocator::order_for_size(1), 0);
+        assert_eq!(BuddyFrameAllocator::order_for_size(4096), 0);
+        assert_eq!(BuddyFrameAllocator::order_for_size(4097), 1);
+        assert_eq!(BuddyFrameAllocator::order_for_size(2 * 1024 * 1024), ORDER_2MIB);
+    }
+
+    #[test]
+    #[should_panic]
+    fn double_free_panics() {
+        let (mut allocator, _memory) = construct_allocator();
+
+        let frame: PhysFrame = allocator.allocate_frame().unwrap();
+        allocator.deallocate_frame(frame);
+        allocator.deallocate_frame(frame);
+    }
+
+    #[test]
+    #[should_panic]
+    fn freeing_frame_of_merged_block_panics() {
+        let (mut allocator, _memory) = construct_allocator();
+
+        let block = allocator.allocate(1).unwrap();
+        allocator.deallocate(block, 1);
+        allocator.deallocate(block + FRAME_SIZE, 0);  // second frame of freed block
+    }
+
+    // free blocks store their links in themselves, so returned buffer
+    // acts as complete physical memory and must outlive the allocator
+    fn construct_allocator() -> (BuddyFrameAllocator, Box<[u64]>) {
+        let mut memory_map = MemoryMap::new();
+        memory_map.add_region(MemoryRegion {
+            range: FrameRange::new(0, MEMORY_SIZE),
+            region_type: MemoryRegionType::Usable,
+        });
+
+        let mut memory = vec![0u64; (MEMORY_SIZE / 8) as usize].into_boxed_slice();
+        let physical_memory_offset = memory.as_mut_ptr() as u64;
+        let allocator = unsafe { BuddyFrameAllocator::init(&memory_map, physical_memory_offset) };
+
+        (allocator, memory)
+    }
+}
@@ -0,0 +1,196 @@
+use core::ptr;
+use alloc::collections::BTreeMap;
+
+use spin::Mutex;
+use lazy_static::lazy_static;
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::registers::control::Cr3;
+use x86_64::instructions::{interrupts, tlb};
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::{PageTable, PageTableFlags, page_table::PageTableEntry};
+
+use super::buddy::MAX_ORDER;
+use super::{BuddyFrameAllocator, with_frame_allocator, try_with_page_tables, frame_to_page_table, phys_to_virt};
+
+// marks a page which is shared with other address spaces, it's mapped
+// read-only and copied on first write
+pub const COW: PageTableFlags = PageTableFlags::BIT_9;
+// marks a page whose frame was allocated by kernel on behalf of the
+// address space (copy of a shared page), it's freed with the space
+pub const OWNED: PageTableFlags = PageTableFlags::BIT_10;
+
+lazy_static! {
+    // lives on heap, so sharing pages needs `allocator::init_heap` to be
+    // called first; locked with interrupts disabled, so page fault
+    // handler never finds it held by a preempted thread
+    static ref FRAME_REFS: Mutex<FrameRefs> = Mutex::new(FrameRefs::new());
+}
+
+// add a reference to given frame
+pub fn share(frame: PhysAddr) {
+    interrupts::without_interrupts(|| FRAME_REFS.lock().share(frame))
+}
+
+// drop a reference to given frame, returns true if it was the last one
+// i.e. frame can be freed now
+pub fn release(frame: PhysAddr) -> bool {
+    interrupts::without_interrupts(|| FRAME_REFS.lock().release(frame))
+}
+
+pub fn ref_count(frame: PhysAddr) -> usize {
+    interrupts::without_interrupts(|| FRAME_REFS.lock().count(frame))
+}
+
+// number of address spaces mapping a shared frame, frames not in here
+// have a single owner
+struct FrameRefs {
+    counts: BTreeMap<PhysAddr, usize>,
+}
+
+impl FrameRefs {
+    fn new() -> Self {
+        FrameRefs { counts: BTreeMap::new() }
+    }
+
+    fn share(&mut self, frame: PhysAddr) {
+        *self.counts.entry(frame).or_insert(1) += 1;
+    }
+
+    fn release(&mut self, frame: PhysAddr) -> bool {
+        match self.counts.get_mut(&frame) {
+            Some(count) => {
+                *count -= 1;
+                if *count == 1 {
+                    self.counts.remove(&frame);
+                }
+                false
+            },
+            None => true,
+        }
+    }
+
+    fn count(&self, frame: PhysAddr) -> usize {
+        self.counts.get(&frame).cloned().unwrap_or(1)
+    }
+}
+
+// leaf entry is about to be removed from a table of given level (1 for
+// level 1 tables), drop its reference and free the frame if nobody else
+// uses it; frames not marked by `COW` or `OWNED` belong to whoever mapped them
+pub(super) fn release_entry(entry: &PageTableEntry, level: usize) {
+    let flags = entry.flags();
+    let last = if flags.contains(COW) {
+        release(entry.addr())
+    } else {
+        flags.contains(OWNED)
+    };
+    let order = BuddyFrameAllocator::order_for_size(page_size(level));
+    if last && order <= MAX_ORDER {  // 1GiB frames don't come from frame allocator
+        with_frame_allocator(|allocator| allocator.deallocate(entry.addr(), order));
+    }
+}
+
+// called by page fault handler, resolves write access to a present
+// page marked `COW` in active level 4 table by giving it a private
+// copy of the frame; returns false if fault is not a valid CoW fault
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    let cow_fault = PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+    if !error_code.contains(cow_fault) {
+        return false;
+    }
+    // gives up instead of deadlocking if fault happened while changing page tables
+    try_with_page_tables(|| copy_on_write(addr, error_code)).unwrap_or(false)
+}
+
+// give page mapping given address a private copy of its frame
+fn copy_on_write(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    let (entry, level) = match leaf_entry(addr) {
+        Some(leaf) => leaf,
+        None => return false,
+    };
+    let flags = entry.flags();
+    let user_denied = error_code.contains(PageFaultErrorCode::USER_MODE)
+        && !flags.contains(PageTableFlags::USER_ACCESSIBLE);
+    if !flags.contains(COW) || user_denied {
+        return false;
+    }
+
+    let size = page_size(level);
+    let old = entry.addr();
+    let new_flags = (flags - COW) | PageTableFlags::WRITABLE | OWNED;
+    if ref_count(old) == 1 {
+        entry.set_flags(new_flags);  // other spaces are gone, no need to copy
+    } else {
+        let order = BuddyFrameAllocator::order_for_size(size);
+        let new = match with_frame_allocator(|allocator| allocator.allocate(order)) {
+            Some(new) => new,
+            None => return false,
+        };
+        unsafe {
+            let src: *const u8 = phys_to_virt(old).as_ptr();
+            let dst: *mut u8 = phys_to_virt(new).as_mut_ptr();
+            ptr::copy_nonoverlapping(src, dst, size as usize);
+        }
+        entry.set_addr(new, new_flags);
+        release(old);
+    }
+    tlb::flush(VirtAddr::new(addr.as_u64() & !(size - 1)));
+
+    true
+}
+
+// size of page mapped by a leaf entry in table of given level
+pub(super) fn page_size(level: usize) -> u64 {
+    4096 << (9 * (level - 1))
+}
+
+// entry in active level 4 table which maps given address and level of
+// the table it's in
+fn leaf_entry(addr: VirtAddr) -> Option<(&'static mut PageTableEntry, usize)> {
+    let indices = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+
+    let mut table: &mut PageTable = unsafe { &mut *frame_to_page_table(Cr3::read().0) };
+    for (i, &index) in indices.iter().enumerate() {
+        let level = 4 - i;
+        let entry = &mut table[index];
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        let huge = level != 4 && entry.flags().contains(PageTableFlags::HUGE_PAGE);
+        if level == 1 || huge {
+            return Some((entry, level));
+        }
+        table = unsafe { &mut *frame_to_page_table(entry.frame().ok()?) };
+    }
+
+    None
+}
+
+
+#[cfg(all(test, not(target_os = "none")))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn last_release_frees_frame() {
+        let mut refs = FrameRefs::new();
+        let frame = PhysAddr::new(0x1234_5000);
+        assert_eq!(refs.count(frame), 1);
+
+        refs.share(frame);
+        refs.share(frame);
+        assert_eq!(refs.count(frame), 3);
+
+        assert!(!refs.release(frame));
+        assert!(!refs.release(frame));
+        assert_eq!(refs.count(frame), 1);
+        assert!(refs.release(frame));
+    }
+
+    #[test]
+    fn page_size_by_level() {
+        assert_eq!(page_size(1), 4096);
+        assert_eq!(page_size(2), 2 * 1024 * 1024);
+        assert_eq!(page_size(3), 1024 * 1024 * 1024);
+    }
+}
@@ -0,0 +1,225 @@
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::instructions::tlb;
+use x86_64::structures::paging::{
+    PhysFrame,
+    Page,
+    PageSize,
+    page::PageRange,
+    Size4KiB,
+    Mapper,
+    FrameAllocator,
+    FrameDeallocator,
+    PageTableFlags,
+    mapper::{MapToError, UnmapError, FlagUpdateError},
+};
+
+use super::{phys_to_virt, with_mapper, kernel_p4_frame, GlobalFrameAllocator};
+
+// invalidating single pages gets more expensive than flushing whole TLB
+// (by reloading `Cr3`) somewhere around this many pages
+const FLUSH_ALL_THRESHOLD: u64 = 32;
+
+// virtual area device memory is mapped to by `map_mmio`, addresses are
+// handed out once and never reused
+pub const MMIO_START: u64 = 0x_7777_0000_0000;
+static NEXT_MMIO: AtomicU64 = AtomicU64::new(MMIO_START);
+
+// what virtual range should be mapped to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    Anonymous,  // newly allocated and zeroed frames
+    Physical(PhysAddr),  // contiguous physical range starting at given address, e.g. MMIO
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappingError {
+    Misaligned,  // physical address is not aligned to page size
+    FrameAllocationFailed,
+    PageAlreadyMapped,
+    PageNotMapped,
+    ParentEntryHugePage,  // range overlaps an existing huge page
+    InvalidFrameAddress(PhysAddr),
+    SharedWithKernel,  // user page would land in tables every address space shares
+}
+
+impl From<MapToError> for MappingError {
+    fn from(err: MapToError) -> Self {
+        match err {
+            MapToError::FrameAllocationFailed => MappingError::FrameAllocationFailed,
+            MapToError::ParentEntryHugePage => MappingError::ParentEntryHugePage,
+            MapToError::PageAlreadyMapped => MappingError::PageAlreadyMapped,
+        }
+    }
+}
+
+impl From<FlagUpdateError> for MappingError {
+    fn from(err: FlagUpdateError) -> Self {
+        match err {
+            FlagUpdateError::PageNotMapped => MappingError::PageNotMapped,
+            FlagUpdateError::ParentEntryHugePage => MappingError::ParentEntryHugePage,
+        }
+    }
+}
+
+impl From<UnmapError> for MappingError {
+    fn from(err: UnmapError) -> Self {
+        match err {
+            UnmapError::ParentEntryHugePage => MappingError::ParentEntryHugePage,
+            UnmapError::PageNotMapped => MappingError::PageNotMapped,
+            UnmapError::InvalidFrameAddress(addr) => MappingError::InvalidFrameAddress(addr),
+        }
+    }
+}
+
+// map given pages, page size is picked by type of the range; if any
+// page fails to map, already mapped pages are unmapped again (and their
+// frames freed for anonymous backing) before returning the error
+pub fn map_range<S, M, A>(
+    mapper: &mut M,
+    pages: PageRange<S>,
+    backing: Backing,
+    flags: PageTableFlags,
+    frame_allocator: &mut A,
+) -> Result<(), MappingError>
+    where S: PageSize,
+          M: Mapper<S>,
+          A: FrameAllocator<Size4KiB> + FrameAllocator<S> + FrameDeallocator<S>
+{
+    if let Backing::Physical(addr) = backing {
+        if !addr.is_aligned(S::SIZE) {
+            return Err(MappingError::Misaligned);
+        }
+    }
+
+    let start_page = pages.start;
+    for page in pages {
+        let i = page - start_page;
+        if let Err(err) = map_page(mapper, page, i, backing, flags, frame_allocator) {
+            for page in Page::range(start_page, page) {
+                let (frame, flush) = mapper.unmap(page).expect("rollback of mapped page failed");
+                flush.ignore();
+                if backing == Backing::Anonymous {
+                    frame_allocator.deallocate_frame(frame);
+                }
+            }
+            flush_tlb(start_page, i);
+            return Err(err);
+        }
+    }
+    flush_tlb(start_page, pages.end - start_page);
+
+    Ok(())
+}
+
+// unmap given pages, frames are deallocated only if `deallocate_frames`
+// is set i.e. range was mapped with anonymous backing; all pages are
+// tried even if some of them fail
+pub fn unmap_range<S, M, A>(
+    mapper: &mut M,
+    pages: PageRange<S>,
+    deallocate_frames: bool,
+    frame_allocator: &mut A,
+) -> Result<(), MappingError>
+    where S: PageSize,
+          M: Mapper<S>,
+          A: FrameDeallocator<S>
+{
+    let mut result = Ok(());
+    for page in pages {
+        match mapper.unmap(page) {
+            Ok((frame, flush)) => {
+                flush.ignore();
+                if deallocate_frames {
+                    frame_allocator.deallocate_frame(frame);
+                }
+            },
+            Err(err) => if result.is_ok() {
+                result = Err(err.into());  // report first error only
+            },
+        }
+    }
+    flush_tlb(pages.start, pages.end - pages.start);
+
+    result
+}
+
+// map `size` bytes of device memory starting at given physical address
+// to a new virtual address in kernel level 4 table with caching
+// disabled, returned address has the same offset into the page
+pub fn map_mmio(addr: PhysAddr, size: u64) -> Result<VirtAddr, MappingError> {
+    let start_frame = addr.align_down(Size4KiB::SIZE);
+    let end_frame = (addr + size).align_up(Size4KiB::SIZE);
+    let length = end_frame - start_frame;
+    let virt = VirtAddr::new(NEXT_MMIO.fetch_add(length, Ordering::Relaxed));
+
+    let start_page: Page = Page::containing_address(virt);
+    let pages = Page::range(start_page, start_page + length / Size4KiB::SIZE);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH | PageTableFlags::NO_EXECUTE;
+    with_mapper(kernel_p4_frame(), |mapper| {
+        map_range(mapper, pages, Backing::Physical(start_frame), flags, &mut GlobalFrameAllocator)
+    })?;
+
+    Ok(virt + (addr - start_frame))
+}
+
+// remove mapping made by `map_mmio` with same size, virtual range
+// isn't handed out again
+pub fn unmap_mmio(addr: VirtAddr, size: u64) -> Result<(), MappingError> {
+    let start_page: Page = Page::containing_address(addr);
+    let end_page: Page = Page::containing_address((addr + size).align_up(Size4KiB::SIZE));
+    with_mapper(kernel_p4_frame(), |mapper| {
+        unmap_range(mapper, Page::range(start_page, end_page), false, &mut GlobalFrameAllocator)
+    })
+}
+
+fn map_page<S, M, A>(
+    mapper: &mut M,
+    page: Page<S>,
+    index: u64,
+    backing: Backing,
+    flags: PageTableFlags,
+    frame_allocator: &mut A,
+) -> Result<(), MappingError>
+    where S: PageSize,
+          M: Mapper<S>,
+          A: FrameAllocator<Size4KiB> + FrameAllocator<S> + FrameDeallocator<S>
+{
+    let frame = match backing {
+        Backing::Anonymous => {
+            let frame: PhysFrame<S> = frame_allocator.allocate_frame()
+                .ok_or(MappingError::FrameAllocationFailed)?;
+            let frame_ptr: *mut u8 = phys_to_virt(frame.start_address()).as_mut_ptr();
+            unsafe { ptr::write_bytes(frame_ptr, 0, S::SIZE as usize) };
+            frame
+        },
+        Backing::Physical(addr) => PhysFrame::containing_address(addr + index * S::SIZE),
+    };
+
+    let result = unsafe { mapper.map_to(page, frame, flags, frame_allocator) };
+    match result {
+        Ok(flush) => {
+            flush.ignore();  // whole range is flushed at once
+            Ok(())
+        },
+        Err(err) => {
+            if backing == Backing::Anonymous {
+                frame_allocator.deallocate_frame(frame);
+            }
+            Err(err.into())
+        },
+    }
+}
+
+fn flush_tlb<S: PageSize>(start_page: Page<S>, page_count: u64) {
+    if page_count > FLUSH_ALL_THRESHOLD {
+        tlb::flush_all();
+    } else {
+        for i in 0..page_count {
+            tlb::flush((start_page + i).start_address());
+        }
+    }
+}
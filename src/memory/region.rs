@@ -0,0 +1,244 @@
+use spin::Mutex;
+use x86_64::VirtAddr;
+use x86_64::registers::control::Cr3;
+use x86_64::instructions::{interrupts, tlb};
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::{
+    Mapper,
+    Page,
+    PageSize,
+    Size4KiB,
+    FrameDeallocator,
+    PageTableFlags,
+};
+
+use super::{GlobalFrameAllocator, with_mapper, try_with_mapper, map_range, Backing, MappingError};
+use super::address_space::{check_user_page, set_parents_user_accessible};
+
+const MAX_REGIONS: usize = 32;
+
+// registered regions, looked up by page fault handler; regions are
+// not tied to an address space, a fault is resolved in whichever
+// level 4 table is active at that time; locked with interrupts
+// disabled, so the handler never finds it held by a preempted thread
+static REGIONS: Mutex<RegionTable> = Mutex::new(RegionTable::new());
+
+// how faults inside a region are resolved
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    Lazy,  // zeroed frame is allocated and mapped on first access
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub start: VirtAddr,
+    pub end: VirtAddr,  // exclusive
+    pub kind: RegionKind,
+    pub flags: PageTableFlags,  // flags pages are mapped with
+}
+
+impl Region {
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        addr >= self.start && addr < self.end
+    }
+
+    fn overlaps(&self, other: &Region) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+
+    // whether flags of this region permit the access described by
+    // given error code, reserved bit faults are never permitted
+    fn permits(&self, error_code: PageFaultErrorCode) -> bool {
+        let denied = (error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+                && !self.flags.contains(PageTableFlags::WRITABLE))
+            || (error_code.contains(PageFaultErrorCode::USER_MODE)
+                && !self.flags.contains(PageTableFlags::USER_ACCESSIBLE))
+            || (error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+                && self.flags.contains(PageTableFlags::NO_EXECUTE))
+            || error_code.contains(PageFaultErrorCode::MALFORMED_TABLE);
+        !denied
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionError {
+    Misaligned,  // start or size is not a multiple of page size
+    Empty,
+    Overlapping,
+    TableFull,
+}
+
+// fixed size so that page fault handler never depends on heap
+struct RegionTable {
+    regions: [Option<Region>; MAX_REGIONS],
+}
+
+impl RegionTable {
+    const fn new() -> Self {
+        RegionTable { regions: [None; MAX_REGIONS] }
+    }
+
+    fn insert(&mut self, region: Region) -> Result<(), RegionError> {
+        if self.regions.iter().flatten().any(|r| r.overlaps(&region)) {
+            return Err(RegionError::Overlapping);
+        }
+        let slot = self.regions.iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(RegionError::TableFull)?;
+        *slot = Some(region);
+        Ok(())
+    }
+
+    fn remove(&mut self, start: VirtAddr) -> Option<Region> {
+        self.regions.iter_mut()
+            .find(|slot| slot.map_or(false, |r| r.start == start))
+            .and_then(|slot| slot.take())
+    }
+
+    fn find(&self, addr: VirtAddr) -> Option<Region> {
+        self.regions.iter().flatten().find(|r| r.contains(addr)).cloned()
+    }
+}
+
+// register `size` bytes starting at `start` as region of given kind,
+// nothing is mapped until the region is accessed
+pub fn register(
+    start: VirtAddr,
+    size: u64,
+    kind: RegionKind,
+    flags: PageTableFlags,
+) -> Result<(), RegionError> {
+    if !start.is_aligned(Size4KiB::SIZE) || size % Size4KiB::SIZE != 0 {
+        return Err(RegionError::Misaligned);
+    }
+    if size == 0 {
+        return Err(RegionError::Empty);
+    }
+
+    let region = Region { start, end: start + size, kind, flags: flags | PageTableFlags::PRESENT };
+    interrupts::without_interrupts(|| REGIONS.lock().insert(region))
+}
+
+// remove region starting at `start`, pages of a lazy region which were
+// already populated in active level 4 table are unmapped and freed
+pub fn unregister(start: VirtAddr) -> Option<Region> {
+    let region = interrupts::without_interrupts(|| REGIONS.lock().remove(start))?;
+
+    if region.kind == RegionKind::Lazy {
+        let start_page = Page::<Size4KiB>::containing_address(region.start);
+        let end_page = Page::<Size4KiB>::containing_address(region.end);
+        with_mapper(Cr3::read().0, |mapper| {
+            for page in Page::range(start_page, end_page) {
+                if let Ok((frame, flush)) = mapper.unmap(page) {  // not populated pages are skipped
+                    flush.ignore();
+                    GlobalFrameAllocator.deallocate_frame(frame);
+                }
+            }
+        });
+        tlb::flush_all();
+    }
+
+    Some(region)
+}
+
+// region containing given address, if any
+pub fn find(addr: VirtAddr) -> Option<Region> {
+    interrupts::without_interrupts(|| REGIONS.lock().find(addr))
+}
+
+// called by page fault handler with faulting address from `Cr2`,
+// returns true if fault is resolved and faulting instruction can be
+// retried, false if access is invalid
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    let region = match find(addr) {
+        Some(region) => region,
+        None => return false,
+    };
+    if !region.permits(error_code) {
+        return false;
+    }
+
+    match region.kind {
+        RegionKind::Lazy => {
+            if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+                return false;  // page is already present, so it's not a missing page
+            }
+            populate(Page::containing_address(addr), region.flags)
+        },
+    }
+}
+
+// map a zeroed frame at given page in active level 4 table
+fn populate(page: Page<Size4KiB>, flags: PageTableFlags) -> bool {
+    if check_user_page(Cr3::read().0, page, flags).is_err() {
+        return false;
+    }
+    let pages = Page::range(page, page + 1);
+    let p4_frame = Cr3::read().0;
+    let mapped: Option<Result<(), MappingError>> = try_with_mapper(p4_frame, |mapper| {
+        map_range(mapper, pages, Backing::Anonymous, flags, &mut GlobalFrameAllocator)?;
+        if flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+            set_parents_user_accessible(p4_frame, page);
+        }
+        Ok(())
+    });
+    mapped == Some(Ok(()))
+}
+
+
+#[cfg(all(test, not(target_os = "none")))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn finds_containing_region() {
+        let mut table = RegionTable::new();
+        table.insert(region(0x1000, 0x3000)).unwrap();
+        table.insert(region(0x5000, 0x6000)).unwrap();
+
+        assert_eq!(table.find(VirtAddr::new(0x2fff)), Some(region(0x1000, 0x3000)));
+        assert_eq!(table.find(VirtAddr::new(0x5000)), Some(region(0x5000, 0x6000)));
+        assert_eq!(table.find(VirtAddr::new(0x3000)), None);  // end is exclusive
+    }
+
+    #[test]
+    fn rejects_overlapping_regions() {
+        let mut table = RegionTable::new();
+        table.insert(region(0x1000, 0x3000)).unwrap();
+
+        assert_eq!(table.insert(region(0x2000, 0x4000)), Err(RegionError::Overlapping));
+        assert_eq!(table.insert(region(0x0, 0x1000)), Ok(()));
+    }
+
+    #[test]
+    fn removed_slot_is_reused() {
+        let mut table = RegionTable::new();
+        for i in 0..MAX_REGIONS as u64 {
+            table.insert(region(i * 0x1000, (i + 1) * 0x1000)).unwrap();
+        }
+        let extra = region(0x10_0000, 0x10_1000);
+        assert_eq!(table.insert(extra), Err(RegionError::TableFull));
+
+        assert_eq!(table.remove(VirtAddr::new(0x1000)), Some(region(0x1000, 0x2000)));
+        assert_eq!(table.insert(extra), Ok(()));
+    }
+
+    #[test]
+    fn permits_access_by_flags() {
+        let read_only = region(0x1000, 0x2000);
+        assert!(read_only.permits(PageFaultErrorCode::empty()));
+        assert!(!read_only.permits(PageFaultErrorCode::CAUSED_BY_WRITE));
+        assert!(!read_only.permits(PageFaultErrorCode::USER_MODE));
+        assert!(!read_only.permits(PageFaultErrorCode::INSTRUCTION_FETCH));
+        assert!(!read_only.permits(PageFaultErrorCode::MALFORMED_TABLE));
+    }
+
+    fn region(start: u64, end: u64) -> Region {
+        Region {
+            start: VirtAddr::new(start),
+            end: VirtAddr::new(end),
+            kind: RegionKind::Lazy,
+            flags: PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE,
+        }
+    }
+}
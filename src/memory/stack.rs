@@ -0,0 +1,156 @@
+use core::mem;
+
+use spin::Mutex;
+use x86_64::VirtAddr;
+use x86_64::structures::paging::{Page, PageSize, Size4KiB, PageTableFlags, page::PageRange};
+
+use super::{GlobalFrameAllocator, with_mapper, kernel_p4_frame, map_range, unmap_range, Backing, MappingError};
+
+// virtual area for kernel stacks, split into equally sized slots; a
+// stack occupies top pages of its slot and everything below it is left
+// unmapped, so there is always at least one guard page under a stack
+pub const STACKS_START: u64 = 0x_6666_0000_0000;
+pub const MAX_STACK_PAGES: u64 = 255;  // one page of a slot is always guard
+pub const DEFAULT_STACK_PAGES: u64 = 4;
+const SLOT_PAGES: u64 = MAX_STACK_PAGES + 1;
+const MAX_STACKS: usize = 64;
+
+// name and page count of allocated stacks by slot, used to tell which
+// stack overflowed
+static STACKS: Mutex<[Option<(&'static str, u64)>; MAX_STACKS]> = Mutex::new([None; MAX_STACKS]);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackError {
+    InvalidSize,  // zero or more than `MAX_STACK_PAGES` pages
+    NoFreeSlot,
+    Mapping(MappingError),
+}
+
+// Kernel stack mapped in kernel level 4 table with an unmapped guard
+// page below it, pages are unmapped and freed on drop. Stacks area is
+// mapped in kernel table, so stacks allocated before creating an
+// `AddressSpace` are visible in it too.
+#[derive(Debug)]
+pub struct Stack {
+    slot: usize,
+    pages: u64,
+}
+
+impl Stack {
+    // allocate a stack of given number of pages, name is reported if
+    // the stack overflows
+    pub fn new(pages: u64, name: &'static str) -> Result<Stack, StackError> {
+        if pages == 0 || pages > MAX_STACK_PAGES {
+            return Err(StackError::InvalidSize);
+        }
+
+        let slot = {
+            let mut stacks = STACKS.lock();
+            let slot = stacks.iter().position(|s| s.is_none()).ok_or(StackError::NoFreeSlot)?;
+            stacks[slot] = Some((name, pages));
+            slot
+        };
+        let stack = Stack { slot, pages };
+
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        let result = with_mapper(kernel_p4_frame(), |mapper| {
+            map_range(mapper, stack.page_range(), Backing::Anonymous, flags, &mut GlobalFrameAllocator)
+        });
+        if let Err(err) = result {
+            STACKS.lock()[slot] = None;
+            mem::forget(stack);  // nothing is mapped, so nothing to drop
+            return Err(StackError::Mapping(err));
+        }
+
+        Ok(stack)
+    }
+
+    // initial stack pointer, stack grows down from here
+    pub fn top(&self) -> VirtAddr {
+        slot_start(self.slot + 1)
+    }
+
+    // lowest mapped address
+    pub fn bottom(&self) -> VirtAddr {
+        self.top() - self.pages * Size4KiB::SIZE
+    }
+
+    pub fn guard_page(&self) -> Page {
+        Page::containing_address(self.bottom()) - 1
+    }
+
+    pub fn name(&self) -> &'static str {
+        STACKS.lock()[self.slot].expect("stack slot is free").0
+    }
+
+    // keep the stack mapped forever and return its top, for stacks
+    // which are in use until shutdown e.g. IST stacks
+    pub fn leak(self) -> VirtAddr {
+        let top = self.top();
+        mem::forget(self);
+        top
+    }
+
+    fn page_range(&self) -> PageRange {
+        Page::range(Page::containing_address(self.bottom()), Page::containing_address(self.top()))
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        with_mapper(kernel_p4_frame(), |mapper| {
+            unmap_range(mapper, self.page_range(), true, &mut GlobalFrameAllocator)
+        }).expect("unmapping stack failed");
+        STACKS.lock()[self.slot] = None;
+    }
+}
+
+// name of the stack whose guard area contains given address, if any;
+// this is the stack which overflowed if address caused a page fault
+pub fn guard_owner(addr: VirtAddr) -> Option<&'static str> {
+    let slot = slot_of(addr)?;
+    let stacks = STACKS.try_lock()?;  // don't deadlock if fault happened while allocating a stack
+    let (name, pages) = stacks[slot]?;
+    let bottom = slot_start(slot + 1) - pages * Size4KiB::SIZE;
+    if addr < bottom { Some(name) } else { None }
+}
+
+fn slot_start(slot: usize) -> VirtAddr {
+    VirtAddr::new(STACKS_START + slot as u64 * SLOT_PAGES * Size4KiB::SIZE)
+}
+
+// slot given address belongs to, whether it's mapped or not
+fn slot_of(addr: VirtAddr) -> Option<usize> {
+    let offset = addr.as_u64().checked_sub(STACKS_START)?;
+    let slot = (offset / (SLOT_PAGES * Size4KiB::SIZE)) as usize;
+    if slot < MAX_STACKS { Some(slot) } else { None }
+}
+
+
+#[cfg(all(test, not(target_os = "none")))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn slots_are_contiguous() {
+        assert_eq!(slot_start(0).as_u64(), STACKS_START);
+        assert_eq!(slot_start(1).as_u64(), STACKS_START + SLOT_PAGES * 4096);
+    }
+
+    #[test]
+    fn finds_slot_of_address() {
+        assert_eq!(slot_of(VirtAddr::new(STACKS_START - 1)), None);
+        assert_eq!(slot_of(slot_start(0)), Some(0));
+        assert_eq!(slot_of(slot_start(3) - 1u64), Some(2));
+        assert_eq!(slot_of(slot_start(MAX_STACKS)), None);
+    }
+
+    #[test]
+    fn guard_page_is_below_bottom() {
+        let stack = Stack { slot: 2, pages: DEFAULT_STACK_PAGES };
+        assert_eq!(stack.top(), slot_start(3));
+        assert_eq!(stack.bottom(), slot_start(3) - DEFAULT_STACK_PAGES * 4096);
+        assert_eq!(stack.guard_page().start_address(), stack.bottom() - 4096u64);
+        mem::forget(stack);  // it's not mapped
+    }
+}
@@ -0,0 +1,184 @@
+use x86_64::instructions::{interrupts, port::Port};
+use x86_64::structures::DescriptorTablePointer;
+
+use crate::acpi;
+use crate::hlt_loop;
+
+// PM1 control register bits
+const SCI_EN: u32 = 1;  // set if ACPI mode is enabled
+const SLP_TYP_SHIFT: u32 = 10;
+const SLP_TYP_MASK: u32 = 0b111 << SLP_TYP_SHIFT;
+const SLP_EN: u32 = 1 << 13;
+
+// power management ports of emulators, written with sleep enable and
+// S5 sleep type, used if ACPI tables aren't usable
+const EMULATOR_SHUTDOWN_PORTS: [(u16, u16); 3] = [
+    (0x604, 0x2000),  // QEMU
+    (0xb004, 0x2000),  // Bochs and older QEMU
+    (0x4004, 0x3400),  // VirtualBox
+];
+
+// keyboard controller, pulsing its output line resets the CPU
+const KBC_STATUS: u16 = 0x64;
+const KBC_INPUT_FULL: u8 = 1 << 1;
+const KBC_RESET: u8 = 0xfe;
+
+// AML opcodes needed to find sleep types of S5 object
+const NAME_OP: u8 = 0x08;
+const PACKAGE_OP: u8 = 0x12;
+const BYTE_PREFIX: u8 = 0x0a;
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+
+// power off through ACPI soft-off (S5) state, otherwise through
+// emulator specific ports; halts forever if nothing worked
+pub fn shutdown() -> ! {
+    interrupts::disable();
+
+    if let (Some(fadt), Some((slp_typ_a, slp_typ_b))) = (acpi::fadt(), acpi_sleep_types()) {
+        unsafe { acpi_sleep(fadt, slp_typ_a, slp_typ_b) };
+    }
+
+    for &(port, value) in EMULATOR_SHUTDOWN_PORTS.iter() {
+        unsafe { Port::<u16>::new(port).write(value) };
+    }
+
+    hlt_loop();
+}
+
+// sleep types for PM1a and PM1b control registers to enter S5, if
+// DSDT declares them; needs `acpi::init`
+pub fn acpi_sleep_types() -> Option<(u8, u8)> {
+    acpi::dsdt().and_then(|dsdt| s5_sleep_types(unsafe { dsdt.data() }))
+}
+
+// reset through ACPI reset register or keyboard controller, triple
+// fault if the machine is still running after that
+pub fn reboot() -> ! {
+    interrupts::disable();
+
+    if let Some((register, value)) = acpi::fadt().and_then(|fadt| fadt.reset_register()) {
+        let _ = unsafe { register.write(u32::from(value)) };  // failure is fine, other ways are tried
+    }
+
+    unsafe {
+        let mut status = Port::<u8>::new(KBC_STATUS);
+        for _ in 0..0x10000 {  // controller may not exist, so don't wait forever
+            if status.read() & KBC_INPUT_FULL == 0 {
+                break;
+            }
+        }
+        status.write(KBC_RESET);
+    }
+
+    triple_fault();
+}
+
+// load an empty IDT and raise an exception, CPU can't find any handler
+// including the double fault one, so it resets
+fn triple_fault() -> ! {
+    let idt = DescriptorTablePointer { limit: 0, base: 0 };
+    unsafe {
+        x86_64::instructions::tables::lidt(&idt);
+        asm!("int3" :::: "volatile");
+    }
+    hlt_loop();
+}
+
+// enable ACPI mode if firmware hasn't done it yet and enter S5
+unsafe fn acpi_sleep(fadt: acpi::Fadt, slp_typ_a: u8, slp_typ_b: u8) {
+    let pm1a = match fadt.pm1a_control_block() {
+        Some(pm1a) => pm1a,
+        None => return,
+    };
+
+    if pm1a.read().unwrap_or(0) & SCI_EN == 0 {
+        if let (Some(port), Some(enable)) = (fadt.smi_command_port(), fadt.acpi_enable()) {
+            if port != 0 && enable != 0 {
+                Port::<u8>::new(port as u16).write(enable);
+                for _ in 0..0x10_0000 {  // takes a while, firmware handles it in SMM
+                    if pm1a.read().unwrap_or(0) & SCI_EN != 0 {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    let _ = pm1a.write(sleep_control(pm1a.read().unwrap_or(0), slp_typ_a));
+    if let Some(pm1b) = fadt.pm1b_control_block() {
+        let _ = pm1b.write(sleep_control(pm1b.read().unwrap_or(0), slp_typ_b));
+    }
+}
+
+// PM1 control value entering given sleep type, other bits e.g. `SCI_EN`
+// are kept as they are
+fn sleep_control(current: u32, slp_typ: u8) -> u32 {
+    (current & !SLP_TYP_MASK) | u32::from(slp_typ) << SLP_TYP_SHIFT | SLP_EN
+}
+
+// find `Name(_S5, Package() {a, b, ..})` in AML and return first two
+// elements i.e. sleep types for PM1a and PM1b control registers; it's
+// a byte search instead of interpreting AML, which is enough for how
+// firmware declares this object
+fn s5_sleep_types(aml: &[u8]) -> Option<(u8, u8)> {
+    let is_name = |i: usize| {  // a definition, not a reference to the object
+        let before = |n: usize| i.checked_sub(n).map(|j| aml[j]);
+        before(1) == Some(NAME_OP) || (before(2) == Some(NAME_OP) && before(1) == Some(b'\\'))
+    };
+    let position = (0..aml.len().saturating_sub(3))
+        .find(|&i| &aml[i..i + 4] == b"_S5_" && is_name(i))?;
+
+    let mut bytes = aml[position + 4..].iter().cloned();
+    if bytes.next()? != PACKAGE_OP {
+        return None;
+    }
+    let lead = bytes.next()?;  // package length, bits 6-7 tell how many bytes follow
+    for _ in 0..(lead >> 6) {
+        bytes.next()?;
+    }
+    bytes.next()?;  // number of elements
+
+    let mut element = || match bytes.next()? {
+        BYTE_PREFIX => bytes.next(),
+        ZERO_OP => Some(0),
+        ONE_OP => Some(1),
+        value => Some(value),
+    };
+    let slp_typ_a = element()?;
+    let slp_typ_b = element()?;
+    Some((slp_typ_a, slp_typ_b))
+}
+
+
+#[cfg(all(test, not(target_os = "none")))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sleep_control_keeps_other_bits() {
+        assert_eq!(sleep_control(SCI_EN | 5 << SLP_TYP_SHIFT, 2), SCI_EN | 2 << SLP_TYP_SHIFT | SLP_EN);
+        assert_eq!(sleep_control(0, 0), SLP_EN);
+    }
+
+    #[test]
+    fn finds_s5_sleep_types() {
+        // what QEMU's DSDT has, package of four zeros
+        let aml = [0x10, 0x08, b'_', b'S', b'5', b'_', 0x12, 0x06, 0x04, 0x00, 0x00, 0x00, 0x00];
+        assert_eq!(s5_sleep_types(&aml), Some((0, 0)));
+
+        let aml = [0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x08, 0x02, 0x0a, 0x07, 0x0a, 0x05];
+        assert_eq!(s5_sleep_types(&aml), Some((7, 5)));  // root path
+        let aml = [0x08, b'_', b'S', b'5', b'_', 0x12, 0x48, 0x00, 0x02, 0x0a, 0x07, 0x01];
+        assert_eq!(s5_sleep_types(&aml), Some((7, 1)));
+    }
+
+    #[test]
+    fn ignores_s5_reference() {
+        let aml = [0x70, b'_', b'S', b'5', b'_', 0x12, 0x06, 0x02, 0x05, 0x05];
+        assert_eq!(s5_sleep_types(&aml), None);
+
+        let aml = [0x70, b'_', b'S', b'5', b'_', 0x08, b'_', b'S', b'5', b'_', 0x12, 0x05, 0x02, 0x05, 0x05];
+        assert_eq!(s5_sleep_types(&aml), Some((5, 5)));
+    }
+}
@@ -0,0 +1,260 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::mem;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+use x86_64::instructions::interrupts as cpu_interrupts;
+
+use super::{Task, TaskId};
+use crate::{enable_interrupts_and_hlt, time};
+
+// set by every wake, so that executor knows whether it may halt
+static WOKEN: AtomicBool = AtomicBool::new(false);
+
+static VTABLE: RawWakerVTable = RawWakerVTable::new(clone_waker, wake, wake_by_ref, drop_waker);
+
+// polls tasks only after they are woken and halts CPU when none is
+// woken, so tasks wait for interrupts without spinning
+pub struct Executor {
+    tasks: BTreeMap<TaskId, Entry>,
+}
+
+struct Entry {
+    task: Task,
+    woken: Arc<WakeFlag>,
+    waker: Waker,  // created once, given to every poll of task
+}
+
+// waking only sets flags, so wakers can be used by interrupt handlers;
+// executor keeps a reference to it, so dropping a waker there never
+// frees memory while task is alive
+struct WakeFlag(AtomicBool);
+
+impl WakeFlag {
+    fn wake(&self) {
+        self.0.store(true, Ordering::SeqCst);
+        WOKEN.store(true, Ordering::SeqCst);
+    }
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        Executor { tasks: BTreeMap::new() }
+    }
+
+    // task is polled for the first time on next run
+    pub fn spawn(&mut self, future: impl Future<Output = ()> + 'static) -> TaskId {
+        let task = Task::new(future);
+        let id = task.id();
+        let woken = Arc::new(WakeFlag(AtomicBool::new(false)));
+        let waker = waker(woken.clone());
+        woken.wake();
+        self.tasks.insert(id, Entry { task, woken, waker });
+        id
+    }
+
+    // number of tasks which didn't complete yet
+    pub fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    // poll each woken task once, completed tasks are dropped; returns
+    // number of tasks polled
+    pub fn run_ready(&mut self) -> usize {
+        WOKEN.store(false, Ordering::SeqCst);  // before polling, so that wakes during polls aren't lost
+        let mut polled = 0;
+        let mut completed = Vec::new();
+        for (&id, entry) in self.tasks.iter_mut() {
+            if !entry.woken.0.swap(false, Ordering::SeqCst) {
+                continue;
+            }
+            polled += 1;
+            let mut context = Context::from_waker(&entry.waker);
+            if let Poll::Ready(()) = entry.task.poll(&mut context) {
+                completed.push(id);
+            }
+        }
+        for id in completed {
+            self.tasks.remove(&id);
+        }
+        polled
+    }
+
+    // run tasks and timers until all tasks complete, interrupts must be
+    // enabled
+    pub fn run_until_complete(&mut self) {
+        while !self.is_empty() {
+            self.run_once();
+        }
+    }
+
+    // run tasks and timers forever, replaces `hlt_loop` at end of kernel
+    // main
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.run_once();
+        }
+    }
+
+    // timers are run too as their callbacks may wake tasks
+    fn run_once(&mut self) {
+        time::timer::run_expired();
+        self.run_ready();
+        self.sleep_if_idle();
+    }
+
+    fn sleep_if_idle(&self) {
+        cpu_interrupts::disable();
+        if WOKEN.load(Ordering::SeqCst) || time::timer::has_expired() {
+            cpu_interrupts::enable();
+        } else {
+            enable_interrupts_and_hlt();
+        }
+    }
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Executor::new()
+    }
+}
+
+fn waker(woken: Arc<WakeFlag>) -> Waker {
+    unsafe { Waker::from_raw(raw_waker(Arc::into_raw(woken))) }
+}
+
+fn raw_waker(woken: *const WakeFlag) -> RawWaker {
+    RawWaker::new(woken as *const (), &VTABLE)
+}
+
+// data of each raw waker is a pointer from `Arc::into_raw`, so that
+// wakers count as references to flag
+
+unsafe fn clone_waker(data: *const ()) -> RawWaker {
+    let woken = Arc::from_raw(data as *const WakeFlag);
+    let cloned = woken.clone();
+    mem::forget(woken);  // reference of waker being cloned
+    raw_waker(Arc::into_raw(cloned))
+}
+
+unsafe fn wake(data: *const ()) {
+    Arc::from_raw(data as *const WakeFlag).wake();
+}
+
+unsafe fn wake_by_ref(data: *const ()) {
+    (*(data as *const WakeFlag)).wake();
+}
+
+unsafe fn drop_waker(data: *const ()) {
+    mem::drop(Arc::from_raw(data as *const WakeFlag));
+}
+
+
+#[cfg(all(test, not(target_os = "none")))]
+mod test {
+    use super::*;
+    use core::pin::Pin;
+
+    // pending until polled given number of times, wakes itself each time
+    struct YieldTimes(usize);
+
+    impl Future for YieldTimes {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+            if self.0 == 0 {
+                return Poll::Ready(());
+            }
+            self.0 -= 1;
+            context.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    // pending forever and never wakes
+    struct Never;
+
+    impl Future for Never {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, _context: &mut Context) -> Poll<()> {
+            Poll::Pending
+        }
+    }
+
+    #[test]
+    fn polls_only_woken_tasks() {
+        let mut executor = Executor::new();
+        executor.spawn(YieldTimes(2));
+        executor.spawn(Never);
+        assert_eq!(executor.run_ready(), 2);
+        assert_eq!(executor.run_ready(), 1);
+        assert_eq!(executor.run_ready(), 1);
+        assert_eq!(executor.len(), 1);  // yielding task completed
+        assert_eq!(executor.run_ready(), 0);
+    }
+
+    #[test]
+    fn cloned_waker_wakes_task() {
+        let woken = Arc::new(WakeFlag(AtomicBool::new(false)));
+        let waker = waker(woken.clone());
+        let cloned = waker.clone();
+        assert_eq!(Arc::strong_count(&woken), 3);
+        mem::drop(waker);
+        cloned.wake();
+        assert!(woken.0.load(Ordering::SeqCst));
+        assert_eq!(Arc::strong_count(&woken), 1);
+    }
+}
+
+
+#[cfg(all(test, target_os = "none"))]
+mod kernel_test {
+    use super::*;
+    use core::pin::Pin;
+    use core::time::Duration;
+
+    // completes once a timer woke it, i.e. executor must halt and run
+    // timers in between
+    struct Delay {
+        started: bool,
+        done: Arc<AtomicBool>,
+    }
+
+    impl Future for Delay {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+            if self.done.load(Ordering::SeqCst) {
+                return Poll::Ready(());
+            }
+            if !self.started {
+                self.started = true;
+                let (done, waker) = (self.done.clone(), context.waker().clone());
+                time::timer::after(Duration::from_millis(10), move || {
+                    done.store(true, Ordering::SeqCst);
+                    waker.wake();
+                });
+            }
+            Poll::Pending
+        }
+    }
+
+    #[test_case]
+    fn runs_tasks_woken_by_timers() {
+        let mut executor = Executor::new();
+        for _ in 0..3 {
+            executor.spawn(Delay { started: false, done: Arc::new(AtomicBool::new(false)) });
+        }
+        let start = time::ticks();
+        executor.run_until_complete();
+        assert!(time::ticks() >= start + 10);
+    }
+}
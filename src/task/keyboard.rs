@@ -0,0 +1,175 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+
+use futures_core::Stream;
+use futures_util::future;
+use futures_util::stream::StreamExt;
+use futures_util::task::AtomicWaker;
+use lazy_static::lazy_static;
+use pc_keyboard::{layouts, DecodedKey, Keyboard, ScancodeSet1};
+use spin::Once;
+
+use crate::{debug, print, println};
+
+const QUEUE_SIZE: usize = 100;
+
+// filled by keyboard interrupt handler, created by `ScancodeStream::new`
+// so that it's not allocated in interrupt context; scancodes arriving
+// before it exists are dropped
+static QUEUE: Once<ScancodeQueue> = Once::new();
+
+lazy_static! {
+    static ref WAKER: AtomicWaker = AtomicWaker::new();
+}
+
+// ring buffer with one producer (interrupt handler) and one consumer
+// (`ScancodeStream`), so it needs no lock; one slot is always left
+// empty to tell a full queue from an empty one
+struct ScancodeQueue {
+    slots: Box<[AtomicU8]>,
+    head: AtomicUsize,  // next slot to pop, only moved by consumer
+    tail: AtomicUsize,  // next slot to push, only moved by producer
+}
+
+impl ScancodeQueue {
+    fn new(capacity: usize) -> Self {
+        let slots: Vec<AtomicU8> = (0..=capacity).map(|_| AtomicU8::new(0)).collect();
+        ScancodeQueue { slots: slots.into_boxed_slice(), head: AtomicUsize::new(0), tail: AtomicUsize::new(0) }
+    }
+
+    fn push(&self, scancode: u8) -> Result<(), u8> {
+        let tail = self.tail.load(Ordering::SeqCst);
+        let next = (tail + 1) % self.slots.len();
+        if next == self.head.load(Ordering::SeqCst) {
+            return Err(scancode);
+        }
+        self.slots[tail].store(scancode, Ordering::SeqCst);
+        self.tail.store(next, Ordering::SeqCst);  // slot is visible to consumer only after this
+        Ok(())
+    }
+
+    fn pop(&self) -> Option<u8> {
+        let head = self.head.load(Ordering::SeqCst);
+        if head == self.tail.load(Ordering::SeqCst) {
+            return None;
+        }
+        let scancode = self.slots[head].load(Ordering::SeqCst);
+        self.head.store((head + 1) % self.slots.len(), Ordering::SeqCst);
+        Some(scancode)
+    }
+}
+
+// called by keyboard interrupt handler, scancode is dropped if queue is
+// full; must not allocate or lock anything
+pub(crate) fn add_scancode(scancode: u8) {
+    if let Some(queue) = QUEUE.r#try() {
+        if queue.push(scancode).is_ok() {
+            WAKER.wake();
+        }
+    }
+}
+
+// scancodes received by keyboard interrupt handler, there can be only
+// one as queue has a single consumer
+pub struct ScancodeStream {
+    _private: (),  // can only be created by `new`
+}
+
+impl ScancodeStream {
+    // panics if a stream was created before
+    pub fn new() -> Self {
+        let mut created = false;
+        QUEUE.call_once(|| {
+            created = true;
+            ScancodeQueue::new(QUEUE_SIZE)
+        });
+        assert!(created, "scancode stream already exists");
+        ScancodeStream { _private: () }
+    }
+}
+
+impl Default for ScancodeStream {
+    fn default() -> Self {
+        ScancodeStream::new()
+    }
+}
+
+impl Stream for ScancodeStream {
+    type Item = u8;
+
+    // waker is registered before checking queue again, otherwise a
+    // scancode arriving in between wouldn't wake task
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<u8>> {
+        let queue = QUEUE.r#try().expect("queue is created with stream");
+        if let Some(scancode) = queue.pop() {
+            return Poll::Ready(Some(scancode));
+        }
+
+        WAKER.register(context.waker());
+        match queue.pop() {
+            Some(scancode) => {
+                WAKER.take();
+                Poll::Ready(Some(scancode))
+            },
+            None => Poll::Pending,
+        }
+    }
+}
+
+// task decoding scancodes and printing typed keys, what keyboard
+// interrupt handler used to do itself; typed lines are run as debug
+// commands
+pub fn print_keypresses() -> impl Future<Output = ()> {
+    let mut keyboard = Keyboard::new(layouts::Us104Key, ScancodeSet1);
+    let mut line = String::new();
+
+    ScancodeStream::new().for_each(move |scancode| {
+        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {  // add_byte returns Result<Option<KeyEvent>>
+            if let Some(key) = keyboard.process_keyevent(key_event) {  // process_keyevent returns Option<DecodedKey>
+                match key {  // DecodedKey has two variants - Unicode(char) and RawKey(KeyCode)
+                    DecodedKey::Unicode('\n') => {
+                        println!();
+                        debug::run(&line);
+                        line.clear();
+                    },
+                    DecodedKey::Unicode('\u{8}') => {
+                        line.pop();
+                    },
+                    DecodedKey::Unicode(c) => {
+                        line.push(c);
+                        print!("{}", c);
+                    },
+                    DecodedKey::RawKey(c) => print!("{:?}", c),
+                }
+            }
+        }
+        future::ready(())
+    })
+}
+
+
+#[cfg(all(test, not(target_os = "none")))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn queue_keeps_order_and_capacity() {
+        let queue = ScancodeQueue::new(3);
+        assert_eq!(queue.pop(), None);
+        for scancode in 1..=3 {
+            assert_eq!(queue.push(scancode), Ok(()));
+        }
+        assert_eq!(queue.push(4), Err(4));
+        assert_eq!(queue.pop(), Some(1));
+        assert_eq!(queue.push(4), Ok(()));  // wraps around
+        assert_eq!(queue.pop(), Some(2));
+        assert_eq!(queue.pop(), Some(3));
+        assert_eq!(queue.pop(), Some(4));
+        assert_eq!(queue.pop(), None);
+    }
+}
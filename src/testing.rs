@@ -0,0 +1,251 @@
+use core::fmt;
+use core::intrinsics;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use spin::Once;
+use x86_64::structures::idt::InterruptStackFrameValue;
+
+use crate::{apic, exit_qemu, hlt_loop, interrupts, time, serial_print, serial_println, QemuExitCode};
+use crate::serial::SERIAL1;
+
+// test kernel ticks with `time::DEFAULT_FREQUENCY`, so a test is
+// failed if it runs for more than 10 seconds
+pub const DEFAULT_TIMEOUT_TICKS: u64 = 10 * time::DEFAULT_FREQUENCY as u64;
+
+// local APIC timer counts of watchdog NMI period, a second with
+// QEMU's 1GHz APIC timer; timer interrupts must keep coming within
+// this many periods
+const NMI_PERIOD_COUNT: u32 = 62_500_000;
+const NMI_STALLED_PERIODS: u64 = 2;
+
+// tests collected by `custom_test_frameworks`, kept so that run can
+// continue with next test after a panic
+static TESTS: Once<&'static [&'static dyn Testable]> = Once::new();
+static CURRENT: AtomicUsize = AtomicUsize::new(0);  // index of running test
+static PASSED: AtomicUsize = AtomicUsize::new(0);
+static FAILED: AtomicUsize = AtomicUsize::new(0);
+static ELAPSED_TICKS: AtomicU64 = AtomicU64::new(0);  // of running test
+static TIMEOUT_TICKS: AtomicU64 = AtomicU64::new(DEFAULT_TIMEOUT_TICKS);
+
+// timer interrupts seen by watchdog, checked by watchdog NMI
+static WATCHDOG_TICKS: AtomicU64 = AtomicU64::new(0);
+static NMI_STARTED: AtomicBool = AtomicBool::new(false);
+static NMI_LAST_TICKS: AtomicU64 = AtomicU64::new(0);
+static NMI_STALLED: AtomicU64 = AtomicU64::new(0);  // periods without a timer interrupt
+static EXPECT_TIMEOUT: AtomicBool = AtomicBool::new(false);
+
+// anything marked with `#[test_case]`, functions taking no argument
+// are tests already, `ShouldPanic` is for tests which must panic
+pub trait Testable: Sync {
+    fn name(&self) -> &'static str;
+    fn run(&self);
+
+    fn should_panic(&self) -> bool {
+        false
+    }
+}
+
+impl<T: Fn() + Sync> Testable for T {
+    fn name(&self) -> &'static str {
+        unsafe { intrinsics::type_name::<T>() }  // full path of function
+    }
+
+    fn run(&self) {
+        self()
+    }
+}
+
+// test which passes only if it panics, use `should_panic!` to create
+pub struct ShouldPanic {
+    pub name: &'static str,
+    pub test: fn(),
+}
+
+impl Testable for ShouldPanic {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn run(&self) {
+        (self.test)()
+    }
+
+    fn should_panic(&self) -> bool {
+        true
+    }
+}
+
+// wrap a test function which must panic, to be used as
+// `#[test_case] const NAME: ShouldPanic = should_panic!(function);`
+#[macro_export]
+macro_rules! should_panic {
+    ($test:ident) => ($crate::testing::ShouldPanic {
+        name: concat!(module_path!(), "::", stringify!($test)),
+        test: $test,
+    });
+}
+
+// test runner of kernel tests, prints name and result of each test
+// on serial port and exits QEMU with failure code if any test failed
+pub fn runner(tests: &'static [&'static dyn Testable]) {
+    serial_println!("running {} tests", tests.len());
+    TESTS.call_once(|| tests);
+    run_from(0);
+}
+
+// fail running test if it takes longer than timeout; if APIC is
+// enabled already, an NMI also checks that timer interrupts keep
+// coming, which catches hangs with interrupts disabled e.g. a deadlock
+// inside an interrupt handler
+pub fn start_watchdog() -> Result<interrupts::HandlerId, interrupts::ClaimError> {
+    let id = interrupts::claim_irq(interrupts::TIMER_IRQ, watchdog_tick)?;
+    if apic::is_enabled() {
+        NMI_STARTED.store(true, Ordering::SeqCst);
+        apic::start_nmi_timer(NMI_PERIOD_COUNT);
+    }
+    Ok(id)
+}
+
+// for test binaries checking watchdog itself, a timeout exits QEMU
+// with success instead of failure
+pub fn expect_timeout() {
+    EXPECT_TIMEOUT.store(true, Ordering::SeqCst);
+}
+
+// called by NMI handler, returns false if watchdog isn't using NMIs
+// i.e. NMI came from somewhere else
+pub(crate) fn watchdog_nmi(frame: &InterruptStackFrameValue) -> bool {
+    if !NMI_STARTED.load(Ordering::SeqCst) {
+        return false;
+    }
+    let ticks = WATCHDOG_TICKS.load(Ordering::SeqCst);
+    if NMI_LAST_TICKS.swap(ticks, Ordering::SeqCst) != ticks {
+        NMI_STALLED.store(0, Ordering::SeqCst);
+        return true;
+    }
+    let stalled = NMI_STALLED.fetch_add(1, Ordering::SeqCst) + 1;
+    if stalled >= NMI_STALLED_PERIODS {
+        timed_out(format_args!("no timer interrupt for {} watchdog periods", stalled), Some(frame));
+    }
+    true
+}
+
+// change timeout of running test, it's reset to default before every
+// test
+pub fn set_timeout(ticks: u64) {
+    TIMEOUT_TICKS.store(ticks, Ordering::SeqCst);
+}
+
+// called by panic handler of test kernel, marks current test as passed
+// if it should panic or failed otherwise and continues with next test;
+// there is no unwinding, so stack of panicked test is just abandoned
+// and locks held by it stay locked
+pub fn panic_handler(info: &PanicInfo) -> ! {
+    let tests = match TESTS.r#try() {
+        Some(tests) => tests,
+        None => {  // panicked before tests started
+            serial_println!("[failed]\n{}", info);
+            unsafe { exit_qemu(QemuExitCode::Failed); }
+            hlt_loop();
+        },
+    };
+
+    let current = CURRENT.load(Ordering::SeqCst);
+    if tests[current].should_panic() {
+        serial_println!("[ok]");
+        PASSED.fetch_add(1, Ordering::SeqCst);
+    } else {
+        serial_println!("[failed]\n{}", info);
+        FAILED.fetch_add(1, Ordering::SeqCst);
+    }
+    run_from(current + 1);
+}
+
+fn run_from(first: usize) -> ! {
+    let tests = TESTS.r#try().expect("tests are not set");
+    for (i, test) in tests.iter().enumerate().skip(first) {
+        CURRENT.store(i, Ordering::SeqCst);
+        TIMEOUT_TICKS.store(DEFAULT_TIMEOUT_TICKS, Ordering::SeqCst);
+        ELAPSED_TICKS.store(0, Ordering::SeqCst);
+        serial_print!("{} ... ", test.name());
+        test.run();
+
+        if test.should_panic() {
+            serial_println!("[failed]\ntest did not panic");
+            FAILED.fetch_add(1, Ordering::SeqCst);
+        } else {
+            serial_println!("[ok]");
+            PASSED.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    let (passed, failed) = (PASSED.load(Ordering::SeqCst), FAILED.load(Ordering::SeqCst));
+    let result = if failed == 0 { "ok" } else { "FAILED" };
+    serial_println!("\ntest result: {}. {} passed; {} failed", result, passed, failed);
+
+    let exit_code = if failed == 0 { QemuExitCode::Success } else { QemuExitCode::Failed };
+    unsafe { exit_qemu(exit_code); }
+    hlt_loop();
+}
+
+fn watchdog_tick() {
+    WATCHDOG_TICKS.fetch_add(1, Ordering::SeqCst);
+    if TESTS.r#try().is_none() {
+        return;
+    }
+    let elapsed = ELAPSED_TICKS.fetch_add(1, Ordering::SeqCst) + 1;
+    let timeout = TIMEOUT_TICKS.load(Ordering::SeqCst);
+    if elapsed > timeout {
+        let frame = interrupts::interrupted_frame();
+        timed_out(format_args!("timed out after {} ticks", timeout), frame.as_ref());
+    }
+}
+
+// a hung test can't be recovered from, so whole run is failed; serial
+// port is unlocked forcefully as the test might have hung while
+// holding it
+fn timed_out(reason: fmt::Arguments, frame: Option<&InterruptStackFrameValue>) -> ! {
+    unsafe { SERIAL1.force_unlock(); }
+    if EXPECT_TIMEOUT.load(Ordering::SeqCst) {
+        serial_println!("ok");
+        unsafe { exit_qemu(QemuExitCode::Success); }
+        hlt_loop();
+    }
+
+    let name = TESTS.r#try().map_or("kernel", |tests| tests[CURRENT.load(Ordering::SeqCst)].name());
+    serial_println!("[failed]\n{} {}", name, reason);
+    if let Some(frame) = frame {
+        serial_println!("interrupted at {:#?}", frame);
+    }
+    unsafe { exit_qemu(QemuExitCode::Failed); }
+    hlt_loop();
+}
+
+
+#[cfg(all(test, target_os = "none"))]
+mod kernel_test {
+    use super::*;
+
+    #[test_case]
+    fn runs_plain_function() {
+        assert_eq!(1 + 1, 2);
+    }
+
+    fn failing_assertion() {
+        assert_eq!(1 + 1, 3);
+    }
+
+    #[test_case]
+    const FAILING_ASSERTION: ShouldPanic = should_panic!(failing_assertion);
+
+    #[test_case]
+    fn watchdog_counts_ticks() {
+        let vector = interrupts::irq_vector(interrupts::TIMER_IRQ);
+        let count = interrupts::interrupt_count(vector);
+        while ELAPSED_TICKS.load(Ordering::SeqCst) < 2 {
+            x86_64::instructions::hlt();
+        }
+        assert!(interrupts::interrupt_count(vector) >= count + 2);
+    }
+}
@@ -0,0 +1,747 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+
+use spin::Mutex;
+use x86_64::instructions::{self, interrupts as cpu_interrupts};
+
+use crate::interrupts::{self, HandlerId, ClaimError};
+use crate::memory::stack::{Stack, StackError, DEFAULT_STACK_PAGES};
+use crate::time;
+
+use self::policy::{Policy, RoundRobin};
+
+mod context;
+pub mod policy;
+
+// ticks a thread may run before it's preempted if policy doesn't say
+// otherwise, and other threads are ready
+pub const TIME_SLICE_TICKS: u64 = 10;
+
+// created by `init`, users lock it with interrupts disabled so that
+// timer interrupt never finds it locked
+static SCHEDULER: Mutex<Option<Scheduler>> = Mutex::new(None);
+static SLICE_TICKS: AtomicU64 = AtomicU64::new(0);  // used by current thread
+static SLICE_LENGTH: AtomicU64 = AtomicU64::new(TIME_SLICE_TICKS);  // of current thread
+static NEED_RESCHED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Ready,
+    Running,
+    Sleeping(u64),  // until tick
+    Joining(ThreadId),
+    Dead,
+}
+
+// how policies should favor a thread, round-robin ignores it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    High,
+    Normal,
+    Low,
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority::Normal
+    }
+}
+
+// counted since thread was created
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub cpu_nanos: u64,
+    pub voluntary_switches: u64,  // yielded or blocked
+    pub preemptions: u64,
+}
+
+impl Stats {
+    pub fn context_switches(&self) -> u64 {
+        self.voluntary_switches + self.preemptions
+    }
+}
+
+// snapshot of a thread, see `threads`
+#[derive(Debug, Clone, Copy)]
+pub struct ThreadInfo {
+    pub id: ThreadId,
+    pub name: &'static str,
+    pub state: State,
+    pub priority: Priority,
+    pub stats: Stats,
+}
+
+struct Thread {
+    name: &'static str,
+    state: State,
+    priority: Priority,
+    stats: Stats,
+    rsp: u64,  // saved while not running
+    _stack: Option<Stack>,  // freed with thread, main thread runs on boot stack
+    entry: Option<Box<dyn FnOnce() + Send>>,  // taken when thread starts
+    detached: bool,  // dead thread is freed without join
+}
+
+impl Thread {
+    fn new(name: &'static str, priority: Priority, rsp: u64, stack: Option<Stack>,
+           entry: Option<Box<dyn FnOnce() + Send>>) -> Self {
+        Thread {
+            name,
+            state: State::Ready,
+            priority,
+            stats: Stats::default(),
+            rsp,
+            _stack: stack,
+            entry,
+            detached: false,
+        }
+    }
+
+    fn info(&self, id: ThreadId) -> ThreadInfo {
+        ThreadInfo { id, name: self.name, state: self.state, priority: self.priority, stats: self.stats }
+    }
+}
+
+// returned by `spawn`, thread is detached if it's dropped without join
+pub struct JoinHandle<T> {
+    id: ThreadId,
+    result: Arc<Mutex<Option<T>>>,
+}
+
+// keeps threads and their states, policy decides which ready thread
+// runs next; idle thread runs only if no other thread is ready
+struct Scheduler {
+    threads: BTreeMap<ThreadId, Box<Thread>>,  // boxed so that saved `rsp` doesn't move
+    policy: Box<dyn Policy>,
+    current: ThreadId,
+    idle: ThreadId,
+    next_id: u64,
+    switched_at: u64,  // nanos, when CPU time of current thread was last accounted
+}
+
+impl Scheduler {
+    // current flow of execution becomes main thread, its stack pointer
+    // is saved when it's switched away from for the first time
+    fn new(policy: Box<dyn Policy>, now: u64) -> Self {
+        let mut scheduler = Scheduler {
+            threads: BTreeMap::new(),
+            policy,
+            current: ThreadId(0),
+            idle: ThreadId(0),
+            next_id: 0,
+            switched_at: now,
+        };
+        let main = scheduler.insert(Thread::new("main", Priority::Normal, 0, None, None));
+        scheduler.policy.add(main, Priority::Normal);
+        scheduler.set_state(main, State::Running);
+        scheduler.current = main;
+        scheduler
+    }
+
+    // new thread is ready to run
+    fn add(&mut self, name: &'static str, priority: Priority, rsp: u64, stack: Option<Stack>,
+           entry: Option<Box<dyn FnOnce() + Send>>) -> ThreadId {
+        let id = self.insert(Thread::new(name, priority, rsp, stack, entry));
+        self.policy.add(id, priority);
+        self.policy.enqueue(id);
+        id
+    }
+
+    // idle thread is never given to policy, it's run when nothing is ready
+    fn add_idle(&mut self, rsp: u64, stack: Option<Stack>) -> ThreadId {
+        self.idle = self.insert(Thread::new("idle", Priority::Low, rsp, stack, None));
+        self.idle
+    }
+
+    fn insert(&mut self, thread: Thread) -> ThreadId {
+        let id = ThreadId(self.next_id);
+        self.next_id += 1;
+        self.threads.insert(id, Box::new(thread));
+        id
+    }
+
+    fn set_state(&mut self, id: ThreadId, state: State) {
+        self.thread_mut(id).state = state;
+    }
+
+    // account CPU time of current thread and pick thread to run next,
+    // current thread is queued again if it's still running; returns
+    // current and next thread if they differ
+    fn pick_next(&mut self, now: u64, now_nanos: u64, preempted: bool) -> Option<(ThreadId, ThreadId)> {
+        for (&id, thread) in self.threads.iter_mut() {
+            match thread.state {
+                State::Sleeping(until) if until <= now => {
+                    thread.state = State::Ready;
+                    self.policy.enqueue(id);
+                },
+                _ => {},
+            }
+        }
+
+        let current = self.current;
+        let ran = now_nanos.saturating_sub(self.switched_at);
+        self.switched_at = now_nanos;
+        self.thread_mut(current).stats.cpu_nanos += ran;
+        if current != self.idle {
+            self.policy.account(current, ran, preempted);
+        }
+
+        if self.threads[&current].state == State::Running {
+            if self.policy.is_empty() {
+                return None;
+            }
+            self.set_state(current, State::Ready);
+            if current != self.idle {
+                self.policy.enqueue(current);
+            }
+        }
+
+        let next = self.policy.dequeue().unwrap_or(self.idle);
+        self.set_state(next, State::Running);
+        self.current = next;
+        if next == current {
+            return None;
+        }
+        let stats = &mut self.thread_mut(current).stats;
+        if preempted {
+            stats.preemptions += 1;
+        } else {
+            stats.voluntary_switches += 1;
+        }
+        Some((current, next))
+    }
+
+    fn time_slice(&self) -> u64 {
+        if self.current == self.idle { TIME_SLICE_TICKS } else { self.policy.time_slice(self.current) }
+    }
+
+    // threads are moved over to new policy, which gets them all as
+    // ready ones in order of their ids; old one is returned to be freed
+    fn set_policy(&mut self, mut policy: Box<dyn Policy>) -> Box<dyn Policy> {
+        for (&id, thread) in self.threads.iter() {
+            if id == self.idle {
+                continue;
+            }
+            policy.add(id, thread.priority);
+            if thread.state == State::Ready {
+                policy.enqueue(id);
+            }
+        }
+        mem::replace(&mut self.policy, policy)
+    }
+
+    fn set_priority(&mut self, id: ThreadId, priority: Priority) -> bool {
+        if id == self.idle {
+            return false;
+        }
+        match self.threads.get_mut(&id) {
+            Some(thread) => {
+                thread.priority = priority;
+                self.policy.set_priority(id, priority);
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn thread_mut(&mut self, id: ThreadId) -> &mut Thread {
+        self.threads.get_mut(&id).expect("thread doesn't exist")
+    }
+
+    // mark current thread dead and make threads joining it ready
+    fn exit_current(&mut self) {
+        let current = self.current;
+        self.set_state(current, State::Dead);
+        for (&id, thread) in self.threads.iter_mut() {
+            if thread.state == State::Joining(current) {
+                thread.state = State::Ready;
+                self.policy.enqueue(id);
+            }
+        }
+    }
+
+    // dead or already removed
+    fn is_finished(&self, id: ThreadId) -> bool {
+        self.threads.get(&id).map_or(true, |thread| thread.state == State::Dead)
+    }
+
+    fn remove(&mut self, id: ThreadId) -> Option<Box<Thread>> {
+        let thread = self.threads.remove(&id)?;
+        self.policy.remove(id);
+        Some(thread)
+    }
+
+    // dead thread is returned to be freed, otherwise it's freed by
+    // `remove_detached` once it exits
+    fn detach(&mut self, id: ThreadId) -> Option<Box<Thread>> {
+        if self.is_finished(id) {
+            return self.remove(id);
+        }
+        if let Some(thread) = self.threads.get_mut(&id) {
+            thread.detached = true;
+        }
+        None
+    }
+
+    fn remove_detached(&mut self) -> Vec<Thread> {
+        let detached: Vec<ThreadId> = self.threads.iter()
+            .filter(|(_, thread)| thread.detached && thread.state == State::Dead)
+            .map(|(&id, _)| id)
+            .collect();
+        detached.into_iter().filter_map(|id| self.remove(id)).map(|thread| *thread).collect()
+    }
+
+    // where to save stack pointer of `current` and stack pointer to
+    // continue `next` from
+    fn contexts(&mut self, current: ThreadId, next: ThreadId) -> (*mut u64, u64) {
+        let next_rsp = self.threads[&next].rsp;
+        let current = self.threads.get_mut(&current).expect("thread doesn't exist");
+        (&mut current.rsp as *mut u64, next_rsp)
+    }
+}
+
+// make current flow of execution main thread and start preempting
+// threads on timer ticks, needs heap and `time::init`; threads are
+// scheduled round-robin until `set_policy` is called
+pub fn init() -> Result<HandlerId, ClaimError> {
+    let idle = Stack::new(DEFAULT_STACK_PAGES, "idle").expect("allocating idle stack failed");
+    let rsp = context::initial_rsp(&idle, thread_entry);
+    let policy = Box::new(RoundRobin::new());
+    cpu_interrupts::without_interrupts(|| {
+        let mut scheduler = Scheduler::new(policy, time::clock::monotonic_ns());
+        scheduler.add_idle(rsp, Some(idle));
+        *SCHEDULER.lock() = Some(scheduler);
+    });
+    interrupts::claim_irq(interrupts::TIMER_IRQ, tick)
+}
+
+// run `f` in a new thread with its own stack and normal priority
+pub fn spawn<F, T>(name: &'static str, f: F) -> Result<JoinHandle<T>, StackError>
+    where F: FnOnce() -> T + Send + 'static, T: Send + 'static
+{
+    spawn_with_priority(name, Priority::Normal, f)
+}
+
+pub fn spawn_with_priority<F, T>(name: &'static str, priority: Priority, f: F)
+    -> Result<JoinHandle<T>, StackError>
+    where F: FnOnce() -> T + Send + 'static, T: Send + 'static
+{
+    mem::drop(with_scheduler(|scheduler| scheduler.remove_detached()));  // frees their stacks
+
+    let stack = Stack::new(DEFAULT_STACK_PAGES, name)?;
+    let rsp = context::initial_rsp(&stack, thread_entry);
+    let result = Arc::new(Mutex::new(None));
+    let slot = result.clone();
+    let entry: Box<dyn FnOnce() + Send> = Box::new(move || {
+        let value = f();
+        *slot.lock() = Some(value);
+    });
+    let id = with_scheduler(|scheduler| scheduler.add(name, priority, rsp, Some(stack), Some(entry)));
+    Ok(JoinHandle { id, result })
+}
+
+// let other ready threads run before continuing
+pub fn yield_now() {
+    cpu_interrupts::without_interrupts(|| schedule(false));
+}
+
+// block current thread for at least `duration`, rounded up to ticks
+pub fn sleep(duration: Duration) {
+    let until = time::ticks() + time::ticks_for(duration);
+    cpu_interrupts::without_interrupts(|| {
+        with_scheduler(|scheduler| scheduler.set_state(scheduler.current, State::Sleeping(until)));
+        schedule(false);
+    });
+}
+
+pub fn current() -> ThreadId {
+    with_scheduler(|scheduler| scheduler.current)
+}
+
+pub fn name(id: ThreadId) -> Option<&'static str> {
+    with_scheduler(|scheduler| scheduler.threads.get(&id).map(|thread| thread.name))
+}
+
+// returns false if thread doesn't exist or is idle thread
+pub fn set_priority(id: ThreadId, priority: Priority) -> bool {
+    with_scheduler(|scheduler| scheduler.set_priority(id, priority))
+}
+
+// replace scheduling policy, e.g. with `policy::MultilevelQueue` or
+// `policy::FairShare`
+pub fn set_policy(policy: Box<dyn Policy>) {
+    mem::drop(with_scheduler(|scheduler| scheduler.set_policy(policy)));
+}
+
+pub fn policy_name() -> &'static str {
+    with_scheduler(|scheduler| scheduler.policy.name())
+}
+
+// all threads which weren't freed yet, including idle and dead ones;
+// room is reserved before locking scheduler, so it's retried if more
+// threads were spawned meanwhile
+pub fn threads() -> Vec<ThreadInfo> {
+    let mut infos = Vec::new();
+    loop {
+        let filled = with_scheduler(|scheduler| {
+            if scheduler.threads.len() > infos.capacity() {
+                return Err(scheduler.threads.len());
+            }
+            infos.extend(scheduler.threads.iter().map(|(&id, thread)| thread.info(id)));
+            Ok(())
+        });
+        match filled {
+            Ok(()) => return infos,
+            Err(count) => infos.reserve_exact(count),
+        }
+    }
+}
+
+impl<T> JoinHandle<T> {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    // block until thread finished and return what it returned
+    pub fn join(self) -> T {
+        let thread = cpu_interrupts::without_interrupts(|| loop {
+            let finished = with_scheduler(|scheduler| {
+                if scheduler.is_finished(self.id) {
+                    return Some(scheduler.remove(self.id));
+                }
+                scheduler.set_state(scheduler.current, State::Joining(self.id));
+                None
+            });
+            match finished {
+                Some(thread) => break thread,
+                None => schedule(false),
+            }
+        });
+        mem::drop(thread);  // unmaps its stack, so it's done with interrupts enabled
+        let value = self.result.lock().take();
+        value.expect("thread finished without result")
+    }
+}
+
+impl<T> Drop for JoinHandle<T> {
+    fn drop(&mut self) {
+        let id = self.id;
+        mem::drop(with_scheduler(|scheduler| scheduler.detach(id)));
+    }
+}
+
+// called at end of interrupt dispatch, after interrupt is acknowledged
+// and no locks are held; switches to next thread if current one used
+// up its time slice, interrupted thread continues by returning from
+// interrupt when it's switched back to
+pub(crate) fn preempt() {
+    if NEED_RESCHED.swap(false, Ordering::SeqCst) {
+        schedule(true);
+    }
+}
+
+fn tick() {
+    if SLICE_TICKS.fetch_add(1, Ordering::SeqCst) + 1 >= SLICE_LENGTH.load(Ordering::SeqCst) {
+        NEED_RESCHED.store(true, Ordering::SeqCst);
+    }
+}
+
+// switch to next thread if there is one, interrupts must be disabled;
+// state of current thread must be set before if it's not ready anymore
+fn schedule(preempted: bool) {
+    let now = time::ticks();
+    let now_nanos = time::clock::monotonic_ns();
+    let contexts = {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = scheduler.as_mut().expect("threads are not initialized, see `thread::init`");
+        let contexts = scheduler.pick_next(now, now_nanos, preempted)
+            .map(|(current, next)| scheduler.contexts(current, next));
+        SLICE_LENGTH.store(scheduler.time_slice(), Ordering::SeqCst);
+        contexts
+    };
+    SLICE_TICKS.store(0, Ordering::SeqCst);
+    if let Some((current_rsp, next_rsp)) = contexts {
+        unsafe { context::switch(current_rsp, next_rsp) };
+    }
+}
+
+// first thing a new thread runs, it's entered from `schedule` with
+// interrupts disabled; idle thread has no entry and halts instead
+extern "C" fn thread_entry() -> ! {
+    let entry = with_scheduler(|scheduler| {
+        let current = scheduler.current;
+        scheduler.threads.get_mut(&current).and_then(|thread| thread.entry.take())
+    });
+    cpu_interrupts::enable();
+    match entry {
+        Some(entry) => entry(),
+        None => idle(),
+    }
+
+    cpu_interrupts::disable();
+    with_scheduler(|scheduler| scheduler.exit_current());
+    schedule(false);
+    unreachable!("dead thread was scheduled");
+}
+
+// check for ready threads after every interrupt e.g. when a sleeping
+// thread is due
+fn idle() -> ! {
+    loop {
+        instructions::hlt();
+        yield_now();
+    }
+}
+
+fn with_scheduler<F, R>(f: F) -> R
+    where F: FnOnce(&mut Scheduler) -> R
+{
+    cpu_interrupts::without_interrupts(|| {
+        f(SCHEDULER.lock().as_mut().expect("threads are not initialized, see `thread::init`"))
+    })
+}
+
+
+#[cfg(all(test, not(target_os = "none")))]
+mod test {
+    use super::*;
+
+    fn scheduler(threads: usize) -> (Scheduler, Vec<ThreadId>) {
+        let mut scheduler = Scheduler::new(Box::new(RoundRobin::new()), 0);
+        scheduler.add_idle(0, None);
+        let ids = (0..threads).map(|_| scheduler.add("test", Priority::Normal, 0, None, None)).collect();
+        (scheduler, ids)
+    }
+
+    #[test]
+    fn runs_threads_in_turn() {
+        let (mut scheduler, ids) = scheduler(2);
+        let main = scheduler.current;
+        assert_eq!(scheduler.pick_next(0, 0, false), Some((main, ids[0])));
+        assert_eq!(scheduler.pick_next(0, 0, false), Some((ids[0], ids[1])));
+        assert_eq!(scheduler.pick_next(0, 0, false), Some((ids[1], main)));
+        assert_eq!(scheduler.pick_next(0, 0, false), Some((main, ids[0])));
+    }
+
+    #[test]
+    fn keeps_running_only_ready_thread() {
+        let (mut scheduler, _) = scheduler(0);
+        assert_eq!(scheduler.pick_next(0, 0, false), None);
+        assert_eq!(scheduler.threads[&scheduler.current].state, State::Running);
+    }
+
+    #[test]
+    fn idles_until_sleeper_is_due() {
+        let (mut scheduler, _) = scheduler(0);
+        let (main, idle) = (scheduler.current, scheduler.idle);
+        scheduler.set_state(main, State::Sleeping(10));
+        assert_eq!(scheduler.pick_next(5, 0, false), Some((main, idle)));
+        assert_eq!(scheduler.pick_next(9, 0, false), None);
+        assert_eq!(scheduler.pick_next(10, 0, false), Some((idle, main)));
+        assert!(scheduler.policy.is_empty());  // idle is never queued
+    }
+
+    #[test]
+    fn exit_wakes_joining_thread() {
+        let (mut scheduler, ids) = scheduler(1);
+        let main = scheduler.current;
+        scheduler.set_state(main, State::Joining(ids[0]));
+        assert_eq!(scheduler.pick_next(0, 0, false), Some((main, ids[0])));
+        assert!(!scheduler.is_finished(ids[0]));
+
+        scheduler.exit_current();
+        assert!(scheduler.is_finished(ids[0]));
+        assert_eq!(scheduler.pick_next(0, 0, false), Some((ids[0], main)));
+        assert!(scheduler.remove(ids[0]).is_some());
+        assert!(scheduler.is_finished(ids[0]));
+    }
+
+    #[test]
+    fn detached_thread_is_removed_after_exit() {
+        let (mut scheduler, ids) = scheduler(1);
+        assert!(scheduler.detach(ids[0]).is_none());
+        assert!(scheduler.remove_detached().is_empty());
+        scheduler.pick_next(0, 0, false);
+        scheduler.exit_current();
+        assert_eq!(scheduler.remove_detached().len(), 1);
+    }
+
+    #[test]
+    fn counts_cpu_time_and_switches() {
+        let (mut scheduler, ids) = scheduler(1);
+        let main = scheduler.current;
+        assert_eq!(scheduler.pick_next(0, 100, true), Some((main, ids[0])));
+        assert_eq!(scheduler.pick_next(0, 250, false), Some((ids[0], main)));
+
+        let stats = scheduler.threads[&main].stats;
+        assert_eq!((stats.cpu_nanos, stats.preemptions, stats.voluntary_switches), (100, 1, 0));
+        let stats = scheduler.threads[&ids[0]].stats;
+        assert_eq!((stats.cpu_nanos, stats.preemptions, stats.voluntary_switches), (150, 0, 1));
+        assert_eq!(stats.context_switches(), 1);
+    }
+
+    #[test]
+    fn new_policy_gets_ready_threads() {
+        let (mut scheduler, ids) = scheduler(2);
+        scheduler.set_priority(ids[1], Priority::High);
+        scheduler.set_policy(Box::new(policy::MultilevelQueue::new()));
+        let main = scheduler.current;
+        assert_eq!(scheduler.pick_next(0, 0, false), Some((main, ids[1])));
+        assert_eq!(scheduler.policy.name(), "priority");
+        assert!(!scheduler.set_priority(scheduler.idle, Priority::High));
+    }
+}
+
+
+#[cfg(all(test, target_os = "none"))]
+mod kernel_test {
+    use super::*;
+    use x86_64::VirtAddr;
+    use x86_64::structures::paging::{FrameAllocator, Page, PageSize, PageTableFlags, Size4KiB};
+    use crate::memory::{AddressSpace, GlobalFrameAllocator, address_space, cow};
+    use crate::memory::region::{self, RegionKind};
+    use crate::serial_println;
+
+    #[test_case]
+    fn threads_interleave() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let handles: Vec<_> = (0..3).map(|n| {
+            let log = log.clone();
+            spawn("interleave", move || {
+                for step in 0..3 {
+                    serial_println!("thread {} step {}", n, step);
+                    cpu_interrupts::without_interrupts(|| log.lock().push(n));
+                    yield_now();
+                }
+            }).unwrap()
+        }).collect();
+        for handle in handles {
+            handle.join();
+        }
+
+        let log = log.lock();
+        assert_eq!(log.len(), 9);
+        assert!(log.windows(2).all(|pair| pair[0] != pair[1]), "threads ran as {:?}", *log);
+    }
+
+    // first thread spins until second one runs, which only happens if
+    // it's preempted
+    #[test_case]
+    fn preempts_busy_thread() {
+        let flag = Arc::new(AtomicBool::new(false));
+        let cloned = flag.clone();
+        let spinning = spawn("spinning", move || {
+            while !cloned.load(Ordering::SeqCst) {}
+        }).unwrap();
+        let setting = spawn("setting", move || flag.store(true, Ordering::SeqCst)).unwrap();
+        spinning.join();
+        setting.join();
+    }
+
+    // threads are preempted all the time while allocating, allocating
+    // with interrupts disabled must still not hang on heap lock
+    #[test_case]
+    fn allocates_from_preempted_threads() {
+        let end = time::ticks() + 5 * TIME_SLICE_TICKS;
+        let handles: Vec<_> = (0..3).map(|n| {
+            spawn("allocating", move || {
+                let mut allocations = 0;
+                while time::ticks() < end {
+                    let values: Vec<u64> = (0..16).map(|i| i * n).collect();
+                    assert_eq!(values.len(), 16);
+                    allocations += 1;
+                }
+                allocations
+            }).unwrap()
+        }).collect();
+        while time::ticks() < end {
+            let value = cpu_interrupts::without_interrupts(|| Box::new(42));
+            assert_eq!(*value, 42);
+        }
+        for handle in handles {
+            assert!(handle.join() > 0);
+        }
+    }
+
+    // threads fault on copy-on-write and lazy pages while others are
+    // preempted in between looking up regions and reference counts,
+    // fault handler must still not hang on their locks
+    #[test_case]
+    fn faults_from_preempted_threads() {
+        const PAGES: u64 = 16;  // per thread
+        let cow_start = VirtAddr::new(0x1000_0000_0000);
+        let lazy_start = VirtAddr::new(0x5555_0000_0000);
+        let page_addr = |start: VirtAddr, n: u64, i: u64| start + (n * PAGES + i) * Size4KiB::SIZE;
+
+        let mut space = AddressSpace::new().unwrap();
+        for i in 0..3 * PAGES {
+            let page: Page = Page::containing_address(page_addr(cow_start, 0, i));
+            let frame = GlobalFrameAllocator.allocate_frame().unwrap();
+            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | cow::OWNED;
+            unsafe { space.map(page, frame, flags).unwrap() };
+        }
+        let child = space.clone_cow().unwrap();
+        let (shared, _) = child.translate_addr(cow_start).unwrap();
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        region::register(lazy_start, 3 * PAGES * Size4KiB::SIZE, RegionKind::Lazy, flags).unwrap();
+
+        let go = Arc::new(AtomicBool::new(false));
+        let end = time::ticks() + 5 * TIME_SLICE_TICKS;
+        let handles: Vec<_> = (0..3).map(|n| {
+            let go = go.clone();
+            spawn("faulting", move || {
+                while !go.load(Ordering::SeqCst) {}  // stacks are mapped before switching
+                let mut i = 0;
+                while time::ticks() < end {
+                    if i < PAGES {
+                        let cow: *mut u64 = page_addr(cow_start, n, i).as_mut_ptr();
+                        let lazy: *mut u64 = page_addr(lazy_start, n, i).as_mut_ptr();
+                        unsafe {
+                            cow.write_volatile(n);
+                            lazy.write_volatile(n);
+                        }
+                        i += 1;
+                    }
+                    let addr = page_addr(lazy_start, n, PAGES - 1);
+                    assert!(region::find(addr).is_some());
+                    assert!(cow::ref_count(shared) <= 2);
+                }
+                i
+            }).unwrap()
+        }).collect();
+
+        unsafe { space.switch() };
+        go.store(true, Ordering::SeqCst);
+        for handle in handles {
+            assert_eq!(handle.join(), PAGES);
+        }
+        region::unregister(lazy_start).unwrap();  // frees populated pages of active table
+        unsafe { address_space::switch_to_kernel() };
+        drop(space);
+        drop(child);
+    }
+
+    #[test_case]
+    fn sleep_and_join_result() {
+        let handle = spawn("sleeping", || {
+            let start = time::ticks();
+            sleep(Duration::from_millis(20));
+            time::ticks() - start
+        }).unwrap();
+        assert!(handle.join() >= 20);
+    }
+}
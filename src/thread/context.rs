@@ -0,0 +1,56 @@
+use crate::memory::stack::Stack;
+
+// push callee-saved registers and flags, save stack pointer to `[rdi]`
+// and pop same from stack `rsi` of next thread; compiler saves the rest
+// around the call
+global_asm!("
+    .intel_syntax noprefix
+    .global switch_context
+    switch_context:
+        pushfq
+        push rbp
+        push rbx
+        push r12
+        push r13
+        push r14
+        push r15
+        mov [rdi], rsp
+        mov rsp, rsi
+        pop r15
+        pop r14
+        pop r13
+        pop r12
+        pop rbx
+        pop rbp
+        popfq
+        ret
+    .att_syntax prefix
+");
+
+extern "C" {
+    fn switch_context(current_rsp: *mut u64, next_rsp: u64);
+}
+
+const INITIAL_FLAGS: u64 = 0x2;  // interrupts disabled, bit 1 is reserved and always set
+
+// switch to thread saved at `next_rsp`, returns when something switches
+// back to current thread; unsafe as `next_rsp` must come from a
+// previous switch or `initial_rsp`, and `current_rsp` must stay valid
+pub(super) unsafe fn switch(current_rsp: *mut u64, next_rsp: u64) {
+    switch_context(current_rsp, next_rsp);
+}
+
+// lay out registers on a fresh stack as if switched away from, so that
+// switching to it returns into `entry`; a zero return address is left
+// above it, so that stack is aligned like `entry` was called
+pub(super) fn initial_rsp(stack: &Stack, entry: extern "C" fn() -> !) -> u64 {
+    let frame: [u64; 9] = [
+        0, 0, 0, 0, 0, 0,  // r15, r14, r13, r12, rbx, rbp
+        INITIAL_FLAGS,
+        entry as usize as u64,
+        0,
+    ];
+    let rsp = stack.top().as_u64() - core::mem::size_of_val(&frame) as u64;
+    unsafe { (rsp as *mut [u64; 9]).write(frame) };
+    rsp
+}
@@ -0,0 +1,134 @@
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+
+use super::{Priority, ThreadId, TIME_SLICE_TICKS};
+
+pub use self::fair::FairShare;
+pub use self::priority::MultilevelQueue;
+
+mod fair;
+mod priority;
+
+// decides which ready thread runs next and for how long, idle thread
+// is never given to it; called from timer interrupt too, so only `add`
+// may allocate and should reserve room to queue every thread
+pub trait Policy: Send {
+    fn name(&self) -> &'static str;
+
+    // thread was created, or policy is replacing another one
+    fn add(&mut self, id: ThreadId, priority: Priority);
+
+    // thread exited and is freed, it's not queued
+    fn remove(&mut self, id: ThreadId);
+
+    fn set_priority(&mut self, id: ThreadId, priority: Priority);
+
+    // thread became ready
+    fn enqueue(&mut self, id: ThreadId);
+
+    // remove thread which should run next from queue
+    fn dequeue(&mut self) -> Option<ThreadId>;
+
+    fn is_empty(&self) -> bool;
+
+    // running thread used `nanos` of CPU time since it was last
+    // accounted, `preempted` if its time slice ran out
+    fn account(&mut self, id: ThreadId, nanos: u64, preempted: bool);
+
+    // ticks thread may run before it's preempted
+    fn time_slice(&self, id: ThreadId) -> u64;
+}
+
+// policy given name, for switching policies by a debug command
+pub fn by_name(name: &str) -> Option<Box<dyn Policy>> {
+    match name {
+        "round-robin" => Some(Box::new(RoundRobin::new())),
+        "priority" => Some(Box::new(MultilevelQueue::new())),
+        "fair" => Some(Box::new(FairShare::new())),
+        _ => None,
+    }
+}
+
+pub const NAMES: [&str; 3] = ["round-robin", "priority", "fair"];
+
+// every thread gets same time slice in turn, priorities are ignored
+pub struct RoundRobin {
+    ready: VecDeque<ThreadId>,
+    threads: usize,
+}
+
+impl RoundRobin {
+    pub fn new() -> Self {
+        RoundRobin { ready: VecDeque::new(), threads: 0 }
+    }
+}
+
+impl Default for RoundRobin {
+    fn default() -> Self {
+        RoundRobin::new()
+    }
+}
+
+impl Policy for RoundRobin {
+    fn name(&self) -> &'static str {
+        "round-robin"
+    }
+
+    fn add(&mut self, _id: ThreadId, _priority: Priority) {
+        self.threads += 1;
+        self.ready.reserve(self.threads.saturating_sub(self.ready.len()));
+    }
+
+    fn remove(&mut self, _id: ThreadId) {
+        self.threads -= 1;
+    }
+
+    fn set_priority(&mut self, _id: ThreadId, _priority: Priority) {}
+
+    fn enqueue(&mut self, id: ThreadId) {
+        self.ready.push_back(id);
+    }
+
+    fn dequeue(&mut self) -> Option<ThreadId> {
+        self.ready.pop_front()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.ready.is_empty()
+    }
+
+    fn account(&mut self, _id: ThreadId, _nanos: u64, _preempted: bool) {}
+
+    fn time_slice(&self, _id: ThreadId) -> u64 {
+        TIME_SLICE_TICKS
+    }
+}
+
+
+#[cfg(all(test, not(target_os = "none")))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_robin_keeps_order() {
+        let mut policy = RoundRobin::new();
+        for id in 0..3 {
+            policy.add(ThreadId(id), Priority::Normal);
+            policy.enqueue(ThreadId(id));
+        }
+        assert_eq!(policy.dequeue(), Some(ThreadId(0)));
+        policy.enqueue(ThreadId(0));
+        assert_eq!(policy.dequeue(), Some(ThreadId(1)));
+        assert_eq!(policy.dequeue(), Some(ThreadId(2)));
+        assert_eq!(policy.dequeue(), Some(ThreadId(0)));
+        assert!(policy.is_empty());
+    }
+
+    #[test]
+    fn finds_policy_by_name() {
+        for &name in NAMES.iter() {
+            assert_eq!(by_name(name).map(|policy| policy.name()), Some(name));
+        }
+        assert!(by_name("lottery").is_none());
+    }
+}
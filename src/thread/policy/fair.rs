@@ -0,0 +1,169 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use super::Policy;
+use crate::thread::{Priority, ThreadId};
+use crate::time;
+
+const NORMAL_WEIGHT: u64 = 1024;
+const LATENCY_NANOS: u64 = 20_000_000;  // every ready thread should run once within this
+const MIN_SLICE_TICKS: u64 = 1;
+
+// fair share like Linux's CFS, thread with least CPU time scaled by
+// its weight (virtual runtime) runs next
+pub struct FairShare {
+    ready: Vec<ThreadId>,
+    threads: BTreeMap<ThreadId, Entry>,
+    min_vruntime: u64,  // never decreases, new and woken threads start from here
+}
+
+struct Entry {
+    weight: u64,
+    vruntime: u64,
+}
+
+impl FairShare {
+    pub fn new() -> Self {
+        FairShare { ready: Vec::new(), threads: BTreeMap::new(), min_vruntime: 0 }
+    }
+
+    pub fn vruntime(&self, id: ThreadId) -> Option<u64> {
+        self.threads.get(&id).map(|entry| entry.vruntime)
+    }
+
+    fn weight(&self, id: ThreadId) -> u64 {
+        self.threads.get(&id).map_or(NORMAL_WEIGHT, |entry| entry.weight)
+    }
+
+    // share of latency by weight among ready threads and this one
+    fn slice_nanos(&self, id: ThreadId) -> u64 {
+        let weight = self.weight(id);
+        let total: u64 = weight + self.ready.iter().map(|&ready| self.weight(ready)).sum::<u64>();
+        LATENCY_NANOS * weight / total
+    }
+}
+
+impl Default for FairShare {
+    fn default() -> Self {
+        FairShare::new()
+    }
+}
+
+// weights of nice -5, 0 and 5, each step is about 10% CPU time
+fn weight(priority: Priority) -> u64 {
+    match priority {
+        Priority::High => 3121,
+        Priority::Normal => NORMAL_WEIGHT,
+        Priority::Low => 335,
+    }
+}
+
+impl Policy for FairShare {
+    fn name(&self) -> &'static str {
+        "fair"
+    }
+
+    fn add(&mut self, id: ThreadId, priority: Priority) {
+        self.threads.insert(id, Entry { weight: weight(priority), vruntime: self.min_vruntime });
+        let threads = self.threads.len();
+        self.ready.reserve(threads.saturating_sub(self.ready.len()));
+    }
+
+    fn remove(&mut self, id: ThreadId) {
+        self.threads.remove(&id);
+    }
+
+    fn set_priority(&mut self, id: ThreadId, priority: Priority) {
+        if let Some(entry) = self.threads.get_mut(&id) {
+            entry.weight = weight(priority);
+        }
+    }
+
+    // a thread which slept long isn't let to run until it caught up,
+    // it only gets ahead by half of latency
+    fn enqueue(&mut self, id: ThreadId) {
+        let floor = self.min_vruntime.saturating_sub(LATENCY_NANOS / 2);
+        if let Some(entry) = self.threads.get_mut(&id) {
+            entry.vruntime = entry.vruntime.max(floor);
+        }
+        self.ready.push(id);
+    }
+
+    fn dequeue(&mut self) -> Option<ThreadId> {
+        let threads = &self.threads;
+        let vruntime = |id: &ThreadId| threads.get(id).map_or(0, |entry| entry.vruntime);
+        let (index, _) = self.ready.iter().enumerate()
+            .min_by_key(|&(_, id)| (vruntime(id), *id))?;
+        let id = self.ready.swap_remove(index);
+        self.min_vruntime = self.min_vruntime.max(vruntime(&id));
+        Some(id)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.ready.is_empty()
+    }
+
+    fn account(&mut self, id: ThreadId, nanos: u64, _preempted: bool) {
+        if let Some(entry) = self.threads.get_mut(&id) {
+            entry.vruntime += nanos * NORMAL_WEIGHT / entry.weight;
+        }
+    }
+
+    fn time_slice(&self, id: ThreadId) -> u64 {
+        time::ticks_for(Duration::from_nanos(self.slice_nanos(id))).max(MIN_SLICE_TICKS)
+    }
+}
+
+
+#[cfg(all(test, not(target_os = "none")))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn runs_thread_with_least_vruntime() {
+        let mut policy = FairShare::new();
+        for id in 0..3 {
+            policy.add(ThreadId(id), Priority::Normal);
+        }
+        policy.account(ThreadId(0), 3000, true);
+        policy.account(ThreadId(1), 1000, true);
+        policy.account(ThreadId(2), 2000, true);
+        for id in 0..3 {
+            policy.enqueue(ThreadId(id));
+        }
+        assert_eq!(policy.dequeue(), Some(ThreadId(1)));
+        assert_eq!(policy.dequeue(), Some(ThreadId(2)));
+        assert_eq!(policy.dequeue(), Some(ThreadId(0)));
+        assert_eq!(policy.min_vruntime, 3000);
+    }
+
+    #[test]
+    fn weight_scales_vruntime_and_slice() {
+        let mut policy = FairShare::new();
+        let (high, low) = (ThreadId(1), ThreadId(2));
+        policy.add(high, Priority::High);
+        policy.add(low, Priority::Low);
+        policy.account(high, 3121, true);
+        policy.account(low, 335, true);
+        assert_eq!(policy.vruntime(high), Some(1024));
+        assert_eq!(policy.vruntime(low), Some(1024));
+
+        policy.enqueue(low);
+        assert!(policy.slice_nanos(high) > policy.slice_nanos(low));
+    }
+
+    #[test]
+    fn woken_thread_gets_limited_credit() {
+        let mut policy = FairShare::new();
+        let (sleeper, runner) = (ThreadId(1), ThreadId(2));
+        policy.add(sleeper, Priority::Normal);
+        policy.add(runner, Priority::Normal);
+        policy.account(runner, 10 * LATENCY_NANOS, true);
+        policy.enqueue(runner);
+        policy.dequeue();  // moves min vruntime up
+
+        policy.enqueue(sleeper);
+        assert_eq!(policy.vruntime(sleeper), Some(10 * LATENCY_NANOS - LATENCY_NANOS / 2));
+    }
+}
@@ -0,0 +1,159 @@
+use alloc::collections::{BTreeMap, VecDeque};
+
+use super::Policy;
+use crate::thread::{Priority, ThreadId};
+
+const LEVELS: usize = 4;  // lowest one is only reached by demotion
+const TOP_SLICE_TICKS: u64 = 5;  // doubled on each lower level
+const BOOST_NANOS: u64 = 1_000_000_000;  // CPU time after which all threads go back to their base level
+
+// multilevel feedback queue, highest non-empty level runs first and
+// round-robin within a level; a thread drops a level when it uses up
+// its slice and all of them are boosted back periodically
+pub struct MultilevelQueue {
+    levels: [VecDeque<ThreadId>; LEVELS],
+    threads: BTreeMap<ThreadId, Entry>,
+    since_boost: u64,  // nanos
+}
+
+struct Entry {
+    base: usize,
+    level: usize,
+}
+
+impl MultilevelQueue {
+    pub fn new() -> Self {
+        MultilevelQueue { levels: Default::default(), threads: BTreeMap::new(), since_boost: 0 }
+    }
+
+    // level a thread is currently at, for stats
+    pub fn level(&self, id: ThreadId) -> Option<usize> {
+        self.threads.get(&id).map(|entry| entry.level)
+    }
+
+    // reset every thread to its base level, queued threads are moved
+    // to their new level keeping their order
+    fn boost(&mut self) {
+        for entry in self.threads.values_mut() {
+            entry.level = entry.base;
+        }
+        for level in 0..LEVELS {
+            for _ in 0..self.levels[level].len() {
+                let id = self.levels[level].pop_front().expect("level has fewer threads than its length");
+                let new_level = self.threads.get(&id).map_or(level, |entry| entry.level);
+                self.levels[new_level].push_back(id);
+            }
+        }
+    }
+}
+
+impl Default for MultilevelQueue {
+    fn default() -> Self {
+        MultilevelQueue::new()
+    }
+}
+
+fn base_level(priority: Priority) -> usize {
+    match priority {
+        Priority::High => 0,
+        Priority::Normal => 1,
+        Priority::Low => 2,
+    }
+}
+
+impl Policy for MultilevelQueue {
+    fn name(&self) -> &'static str {
+        "priority"
+    }
+
+    // any level may end up holding every thread
+    fn add(&mut self, id: ThreadId, priority: Priority) {
+        let base = base_level(priority);
+        self.threads.insert(id, Entry { base, level: base });
+        let threads = self.threads.len();
+        for level in self.levels.iter_mut() {
+            level.reserve(threads.saturating_sub(level.len()));
+        }
+    }
+
+    fn remove(&mut self, id: ThreadId) {
+        self.threads.remove(&id);
+    }
+
+    // takes effect next time thread is queued
+    fn set_priority(&mut self, id: ThreadId, priority: Priority) {
+        if let Some(entry) = self.threads.get_mut(&id) {
+            entry.base = base_level(priority);
+            entry.level = entry.base;
+        }
+    }
+
+    fn enqueue(&mut self, id: ThreadId) {
+        let level = self.threads.get(&id).map_or(LEVELS - 1, |entry| entry.level);
+        self.levels[level].push_back(id);
+    }
+
+    fn dequeue(&mut self) -> Option<ThreadId> {
+        self.levels.iter_mut().filter_map(|level| level.pop_front()).next()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.levels.iter().all(|level| level.is_empty())
+    }
+
+    fn account(&mut self, id: ThreadId, nanos: u64, preempted: bool) {
+        if preempted {
+            if let Some(entry) = self.threads.get_mut(&id) {
+                entry.level = (entry.level + 1).min(LEVELS - 1);
+            }
+        }
+        self.since_boost += nanos;
+        if self.since_boost >= BOOST_NANOS {
+            self.since_boost = 0;
+            self.boost();
+        }
+    }
+
+    fn time_slice(&self, id: ThreadId) -> u64 {
+        TOP_SLICE_TICKS << self.level(id).unwrap_or(LEVELS - 1)
+    }
+}
+
+
+#[cfg(all(test, not(target_os = "none")))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn higher_priority_runs_first() {
+        let mut policy = MultilevelQueue::new();
+        policy.add(ThreadId(1), Priority::Low);
+        policy.add(ThreadId(2), Priority::High);
+        policy.enqueue(ThreadId(1));
+        policy.enqueue(ThreadId(2));
+        assert_eq!(policy.dequeue(), Some(ThreadId(2)));
+        assert_eq!(policy.dequeue(), Some(ThreadId(1)));
+        assert_eq!(policy.dequeue(), None);
+    }
+
+    #[test]
+    fn preempted_thread_sinks_until_boost() {
+        let mut policy = MultilevelQueue::new();
+        let (busy, interactive) = (ThreadId(1), ThreadId(2));
+        policy.add(busy, Priority::High);
+        policy.add(interactive, Priority::Normal);
+
+        policy.account(busy, 1000, true);
+        policy.account(busy, 1000, true);
+        assert_eq!(policy.level(busy), Some(2));
+        assert_eq!(policy.time_slice(busy), TOP_SLICE_TICKS * 4);
+        policy.account(interactive, 1000, false);  // blocked before its slice ran out
+        assert_eq!(policy.level(interactive), Some(1));
+
+        policy.enqueue(busy);
+        policy.enqueue(interactive);
+        policy.account(interactive, BOOST_NANOS, false);
+        assert_eq!(policy.level(busy), Some(0));
+        assert_eq!(policy.dequeue(), Some(busy));  // moved back to top by boost
+    }
+}
@@ -0,0 +1,164 @@
+use core::sync::atomic::{self, AtomicU32, AtomicU64, Ordering};
+use core::time::Duration;
+
+use x86_64::instructions::{self, interrupts as cpu_interrupts};
+
+use crate::interrupts::{self, HandlerId, ClaimError};
+
+pub use self::clock::{ClockSource, monotonic_ns};
+
+pub mod pit;
+pub mod rtc;
+pub mod hpet;
+pub mod tsc;
+pub mod clock;
+pub mod timer;
+
+pub const DEFAULT_FREQUENCY: u32 = 1000;  // Hz, i.e. a tick every millisecond
+
+// used by `udelay` until `calibrate_delay` is called, high enough that
+// delays are too long rather than too short on any machine
+const UNCALIBRATED_LOOPS_PER_US: u64 = 10_000;
+const CALIBRATION_TICKS: u64 = 10;
+const CALIBRATION_CHUNK: u64 = 1000;  // loops between checking ticks
+
+// timer ticks since `init`, only ever incremented by tick handler
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static DIVISOR: AtomicU32 = AtomicU32::new(0x1_0000);  // PIT's default
+static LOOPS_PER_US: AtomicU64 = AtomicU64::new(UNCALIBRATED_LOOPS_PER_US);
+
+// program PIT to tick with given frequency and start counting ticks
+// and running timers, needs heap for claiming timer IRQ
+pub fn init(frequency: u32) -> Result<HandlerId, ClaimError> {
+    timer::init();
+    let handler_id = interrupts::claim_irq(interrupts::TIMER_IRQ, tick)?;
+    cpu_interrupts::without_interrupts(|| {
+        DIVISOR.store(pit::set_frequency(frequency), Ordering::SeqCst);
+    });
+    Ok(handler_id)
+}
+
+fn tick() {
+    let now = TICKS.fetch_add(1, Ordering::SeqCst) + 1;
+    timer::tick(now);
+}
+
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::SeqCst)
+}
+
+// actual tick frequency in Hz, rounded down
+pub fn frequency() -> u32 {
+    pit::BASE_FREQUENCY / DIVISOR.load(Ordering::SeqCst)
+}
+
+// time since `init`, with a resolution of one tick
+pub fn uptime() -> Duration {
+    ticks_to_duration(ticks(), DIVISOR.load(Ordering::SeqCst))
+}
+
+// time since `init` from best clock source, see `clock::select`
+pub fn monotonic() -> Duration {
+    Duration::from_nanos(monotonic_ns())
+}
+
+// ticks needed to wait for at least `duration`
+pub fn ticks_for(duration: Duration) -> u64 {
+    duration_to_ticks(duration, DIVISOR.load(Ordering::SeqCst))
+}
+
+// halt until given number of ticks passed, interrupts must be enabled
+pub fn sleep_ticks(count: u64) {
+    let end = ticks() + count;
+    while ticks() < end {
+        instructions::hlt();
+    }
+}
+
+// busy wait for given number of microseconds, works with interrupts
+// disabled too; accurate only after `calibrate_delay`
+pub fn udelay(us: u64) {
+    delay_loop(us.saturating_mul(LOOPS_PER_US.load(Ordering::Relaxed)));
+}
+
+// measure how many delay loops fit in a microsecond by running them
+// for a few ticks, interrupts must be enabled
+pub fn calibrate_delay() {
+    let start = ticks();
+    while ticks() == start {  // start at tick boundary
+        atomic::spin_loop_hint();
+    }
+    let end = start + 1 + CALIBRATION_TICKS;
+    let mut loops = 0;
+    while ticks() < end {
+        delay_loop(CALIBRATION_CHUNK);
+        loops += CALIBRATION_CHUNK;
+    }
+
+    let elapsed = ticks_to_duration(CALIBRATION_TICKS, DIVISOR.load(Ordering::SeqCst));
+    let loops_per_us = loops / (elapsed.as_micros() as u64).max(1);
+    LOOPS_PER_US.store(loops_per_us.max(1), Ordering::Relaxed);
+}
+
+fn delay_loop(loops: u64) {
+    for _ in 0..loops {
+        atomic::spin_loop_hint();  // can't be optimized out
+    }
+}
+
+// each tick is `divisor` cycles of PIT's base clock
+fn ticks_to_duration(ticks: u64, divisor: u32) -> Duration {
+    let nanos = u128::from(ticks) * u128::from(divisor) * 1_000_000_000 / u128::from(pit::BASE_FREQUENCY);
+    Duration::new((nanos / 1_000_000_000) as u64, (nanos % 1_000_000_000) as u32)
+}
+
+// rounded up, so that waiting this many ticks takes at least `duration`
+fn duration_to_ticks(duration: Duration, divisor: u32) -> u64 {
+    let cycles = duration.as_nanos() * u128::from(pit::BASE_FREQUENCY);
+    let per_tick = u128::from(divisor) * 1_000_000_000;
+    ((cycles + per_tick - 1) / per_tick) as u64
+}
+
+
+#[cfg(all(test, not(target_os = "none")))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn converts_ticks_to_duration() {
+        assert_eq!(ticks_to_duration(0, 1193), Duration::from_secs(0));
+        assert_eq!(ticks_to_duration(1, 1193), Duration::from_nanos(999_847));
+        assert_eq!(ticks_to_duration(1_193_182, 1), Duration::from_secs(1));
+        assert_eq!(ticks_to_duration(1_000_000_000, 0x1_0000).as_secs(), 54_925_401);  // doesn't overflow
+    }
+
+    #[test]
+    fn converts_duration_to_ticks() {
+        assert_eq!(duration_to_ticks(Duration::from_secs(0), 1193), 0);
+        assert_eq!(duration_to_ticks(Duration::from_nanos(1), 1193), 1);
+        assert_eq!(duration_to_ticks(Duration::from_millis(100), 1193), 101);  // ticks are slightly short of 1 ms
+        assert_eq!(duration_to_ticks(ticks_to_duration(500, 1193), 1193), 500);
+    }
+}
+
+
+#[cfg(all(test, target_os = "none"))]
+mod kernel_test {
+    use super::*;
+
+    #[test_case]
+    fn sleep_advances_uptime() {
+        let start = uptime();
+        sleep_ticks(10);
+        assert!(uptime() - start >= ticks_to_duration(10, DIVISOR.load(Ordering::SeqCst)));
+    }
+
+    #[test_case]
+    fn udelay_is_calibrated() {
+        calibrate_delay();
+        let start = ticks();
+        udelay(20_000);
+        let elapsed = ticks() - start;
+        assert!(elapsed >= 10 && elapsed <= 100, "20 ms took {} ticks", elapsed);  // emulated timing is loose
+    }
+}
@@ -0,0 +1,172 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Once;
+use x86_64::instructions::interrupts as cpu_interrupts;
+
+use super::{hpet::Hpet, tsc::Tsc, pit, ticks, ticks_to_duration, DIVISOR};
+
+// something which counts time, best one available is picked at boot
+pub trait ClockSource: Sync {
+    fn name(&self) -> &'static str;
+
+    // nanoseconds since an arbitrary point, never decreases
+    fn now_ns(&self) -> u64;
+
+    // higher is better, used to pick a source
+    fn rating(&self) -> u32;
+}
+
+// PIT ticks with elapsed part of current tick from its counter, always
+// available after `time::init`
+pub struct PitClock {
+    last: AtomicU64,  // counter may have wrapped before tick is counted
+}
+
+pub struct HpetClock(Hpet);
+
+pub struct TscClock(Tsc);
+
+static PIT: PitClock = PitClock { last: AtomicU64::new(0) };
+static HPET: Once<Option<HpetClock>> = Once::new();
+static TSC: Once<TscClock> = Once::new();
+
+// selected source, published together with its offset so that
+// `monotonic_ns` in an interrupt never sees one without the other
+static CLOCK: Once<Selected> = Once::new();
+
+struct Selected {
+    source: &'static dyn ClockSource,
+    offset: i64,  // makes its time continue from uptime at the time it was selected
+}
+
+impl ClockSource for PitClock {
+    fn name(&self) -> &'static str {
+        "pit"
+    }
+
+    fn now_ns(&self) -> u64 {
+        let (ticks, counter) = cpu_interrupts::without_interrupts(|| (ticks(), pit::read_counter()));
+        let divisor = DIVISOR.load(Ordering::SeqCst);
+        let elapsed_cycles = divisor.saturating_sub(u32::from(counter));
+        let nanos = ticks_to_duration(ticks, divisor).as_nanos() as u64
+            + u64::from(elapsed_cycles) * 1_000_000_000 / u64::from(pit::BASE_FREQUENCY);
+        self.last.fetch_max(nanos, Ordering::SeqCst).max(nanos)
+    }
+
+    fn rating(&self) -> u32 {
+        100
+    }
+}
+
+impl ClockSource for HpetClock {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    fn now_ns(&self) -> u64 {
+        self.0.counter_to_nanos(self.0.counter())
+    }
+
+    fn rating(&self) -> u32 {
+        250
+    }
+}
+
+impl ClockSource for TscClock {
+    fn name(&self) -> &'static str {
+        "tsc"
+    }
+
+    fn now_ns(&self) -> u64 {
+        self.0.cycles_to_nanos(super::tsc::read())
+    }
+
+    // reading TSC is fastest, but it only keeps time if it's invariant
+    fn rating(&self) -> u32 {
+        if self.0.is_invariant() { 300 } else { 50 }
+    }
+}
+
+// probe HPET and calibrate TSC, then use best source for `monotonic_ns`
+// from now on; needs `acpi::init` and timer interrupts enabled, PIT is
+// used until this is called
+pub fn select() -> &'static dyn ClockSource {
+    let hpet = HPET.call_once(|| Hpet::new().ok().map(HpetClock));
+    let tsc = TSC.call_once(|| TscClock(Tsc::calibrate()));
+
+    let mut best: &'static dyn ClockSource = &PIT;
+    let candidates: [Option<&'static dyn ClockSource>; 2] = [
+        hpet.as_ref().map(|hpet| hpet as &dyn ClockSource),
+        Some(tsc),
+    ];
+    for source in candidates.iter().flatten() {
+        if source.rating() > best.rating() {
+            best = *source;
+        }
+    }
+
+    // both clocks are read back to back
+    let selected = CLOCK.call_once(|| cpu_interrupts::without_interrupts(|| {
+        let offset = PIT.now_ns() as i64 - best.now_ns() as i64;
+        Selected { source: best, offset }
+    }));
+    selected.source
+}
+
+// source `monotonic_ns` uses
+pub fn current() -> &'static dyn ClockSource {
+    CLOCK.r#try().map_or(&PIT, |selected| selected.source)
+}
+
+// nanoseconds since `time::init`
+pub fn monotonic_ns() -> u64 {
+    match CLOCK.r#try() {
+        Some(selected) => (selected.source.now_ns() as i64 + selected.offset) as u64,
+        None => PIT.now_ns(),
+    }
+}
+
+pub fn hpet() -> Option<&'static HpetClock> {
+    HPET.r#try().and_then(|hpet| hpet.as_ref())
+}
+
+pub fn tsc() -> Option<&'static TscClock> {
+    TSC.r#try()
+}
+
+pub fn pit() -> &'static PitClock {
+    &PIT
+}
+
+
+#[cfg(all(test, target_os = "none"))]
+mod kernel_test {
+    use super::*;
+    use crate::time;
+
+    // every source must move forward and agree with PIT ticks
+    #[test_case]
+    fn sources_agree_with_ticks() {
+        select();
+        let mut sources: [Option<&'static dyn ClockSource>; 3] = [Some(pit()), None, None];
+        sources[1] = hpet().map(|hpet| hpet as &dyn ClockSource);
+        sources[2] = tsc().map(|tsc| tsc as &dyn ClockSource);
+
+        for source in sources.iter().flatten() {
+            let start = source.now_ns();
+            time::sleep_ticks(20);  // 20 ms
+            let elapsed = source.now_ns() - start;
+            assert!(elapsed >= 15_000_000 && elapsed <= 60_000_000, "{} measured {} ns", source.name(), elapsed);
+        }
+    }
+
+    #[test_case]
+    fn monotonic_time_increases() {
+        let mut last = monotonic_ns();
+        for _ in 0..1000 {
+            let now = monotonic_ns();
+            assert!(now >= last);
+            last = now;
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use core::ptr;
+
+use x86_64::VirtAddr;
+
+use crate::acpi;
+use crate::memory::{self, MappingError};
+
+// register offsets
+const CAPABILITIES: u64 = 0x000;
+const CONFIGURATION: u64 = 0x010;
+const MAIN_COUNTER: u64 = 0x0f0;
+const REGISTERS_SIZE: u64 = 0x400;
+
+const COUNT_SIZE_CAP: u64 = 1 << 13;  // capabilities, set if main counter is 64 bits wide
+const ENABLE: u64 = 1;  // configuration, main counter runs only if set
+const MAX_PERIOD_FS: u64 = 100_000_000;  // 100 ns, spec requires at least 10 MHz
+
+// High Precision Event Timer, only main counter is used
+#[derive(Debug)]
+pub struct Hpet {
+    base: VirtAddr,
+    period_fs: u64,  // femtoseconds per counter increment
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HpetError {
+    NotFound,  // no ACPI table for it
+    InvalidPeriod(u64),
+    Counter32Bit,  // would wrap within minutes, so time would go backwards
+    Mapping(MappingError),
+}
+
+impl Hpet {
+    // map registers of HPET described by ACPI and start its main
+    // counter, `acpi::init` must be called before; registers are
+    // unmapped again if HPET can't be used
+    pub fn new() -> Result<Hpet, HpetError> {
+        let table = acpi::hpet().ok_or(HpetError::NotFound)?;
+        let base = memory::map_mmio(table.base_address(), REGISTERS_SIZE).map_err(HpetError::Mapping)?;
+        let hpet = Hpet { base, period_fs: 0 };
+
+        let capabilities = hpet.read(CAPABILITIES);
+        let period_fs = capabilities >> 32;
+        let error = if capabilities & COUNT_SIZE_CAP == 0 {
+            Some(HpetError::Counter32Bit)
+        } else if period_fs == 0 || period_fs > MAX_PERIOD_FS {
+            Some(HpetError::InvalidPeriod(period_fs))
+        } else {
+            None
+        };
+        if let Some(error) = error {
+            let _ = memory::unmap_mmio(base, REGISTERS_SIZE);  // error of probing is more useful
+            return Err(error);
+        }
+        let configuration = hpet.read(CONFIGURATION);
+        hpet.write(CONFIGURATION, configuration | ENABLE);
+        Ok(Hpet { period_fs, ..hpet })
+    }
+
+    pub fn counter(&self) -> u64 {
+        self.read(MAIN_COUNTER)
+    }
+
+    pub fn frequency(&self) -> u64 {
+        1_000_000_000_000_000 / self.period_fs
+    }
+
+    pub fn counter_to_nanos(&self, counter: u64) -> u64 {
+        fs_to_nanos(counter, self.period_fs)
+    }
+
+    fn read(&self, offset: u64) -> u64 {
+        unsafe { ptr::read_volatile((self.base + offset).as_ptr()) }
+    }
+
+    fn write(&self, offset: u64, value: u64) {
+        unsafe { ptr::write_volatile((self.base + offset).as_mut_ptr(), value) }
+    }
+}
+
+fn fs_to_nanos(counter: u64, period_fs: u64) -> u64 {
+    (u128::from(counter) * u128::from(period_fs) / 1_000_000) as u64
+}
+
+
+#[cfg(all(test, not(target_os = "none")))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn converts_counter_to_nanos() {
+        let qemu_period = 10_000_000;  // 100 MHz
+        assert_eq!(fs_to_nanos(1, qemu_period), 10);
+        assert_eq!(fs_to_nanos(1_000_000_000, qemu_period), 10_000_000_000);
+        assert_eq!(fs_to_nanos(3, 69_841_279), 209);  // 14.318 MHz of real hardware
+    }
+}
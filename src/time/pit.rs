@@ -0,0 +1,59 @@
+use x86_64::instructions::port::Port;
+
+// 8253/8254 programmable interval timer, only channel 0 is used which
+// is connected to IRQ 0
+pub const BASE_FREQUENCY: u32 = 1_193_182;  // Hz, input clock of all channels
+
+const CHANNEL0: u16 = 0x40;
+const COMMAND: u16 = 0x43;
+const SELECT_CHANNEL0: u8 = 0b00 << 6;
+const ACCESS_LATCH: u8 = 0b00 << 4;
+const ACCESS_LOHI: u8 = 0b11 << 4;  // low byte followed by high byte
+const MODE_RATE_GENERATOR: u8 = 0b010 << 1;
+
+// program channel 0 to fire periodically with given frequency, returns
+// divisor of base frequency used, as actual frequency differs a bit
+// from requested one
+pub fn set_frequency(frequency: u32) -> u32 {
+    let divisor = divisor(frequency);
+    unsafe {
+        Port::<u8>::new(COMMAND).write(SELECT_CHANNEL0 | ACCESS_LOHI | MODE_RATE_GENERATOR);
+        let mut channel0 = Port::<u8>::new(CHANNEL0);
+        channel0.write(divisor as u8);  // 65536 is written as 0
+        channel0.write((divisor >> 8) as u8);
+    }
+    divisor
+}
+
+// current count of channel 0, counts down from divisor to 1 once per
+// tick; call with interrupts disabled so that both bytes belong to the
+// same latch
+pub fn read_counter() -> u16 {
+    unsafe {
+        Port::<u8>::new(COMMAND).write(SELECT_CHANNEL0 | ACCESS_LATCH);
+        let channel0 = Port::<u8>::new(CHANNEL0);
+        let low = channel0.read();
+        let high = channel0.read();
+        u16::from(high) << 8 | u16::from(low)
+    }
+}
+
+// divisor is 16 bit, 0 meaning 65536, so frequency is clamped between
+// ~18.2 Hz and base frequency
+fn divisor(frequency: u32) -> u32 {
+    (BASE_FREQUENCY / frequency.max(1)).max(1).min(0x1_0000)
+}
+
+
+#[cfg(all(test, not(target_os = "none")))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn clamps_divisor() {
+        assert_eq!(divisor(1000), 1193);
+        assert_eq!(divisor(1), 0x1_0000);
+        assert_eq!(divisor(0), 0x1_0000);
+        assert_eq!(divisor(2_000_000), 1);
+    }
+}
@@ -0,0 +1,299 @@
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use spin::Mutex;
+use x86_64::instructions::interrupts as cpu_interrupts;
+use x86_64::instructions::port::Port;
+
+use crate::{acpi, interrupts::{self, HandlerId, ClaimError}};
+
+pub const RTC_IRQ: u8 = 8;
+
+// CMOS is accessed by writing register index to address port and then
+// reading or writing data port, bit 7 of index disables NMI so it's
+// always left clear
+const ADDRESS: u16 = 0x70;
+const DATA: u16 = 0x71;
+
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+const STATUS_C: u8 = 0x0c;
+
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;  // status A
+const HOUR_24: u8 = 1 << 1;  // status B
+const BINARY: u8 = 1 << 2;  // status B, BCD otherwise
+const PERIODIC_INTERRUPT: u8 = 1 << 6;  // status B
+const PM: u8 = 1 << 7;  // in hours register in 12 hour mode
+
+// CMOS index and data ports must be used as a pair
+static CMOS: Mutex<()> = Mutex::new(());
+static BOOT_TIME: AtomicU64 = AtomicU64::new(0);  // Unix time when uptime was zero
+static PERIODIC_TICKS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,  // 1 to 12
+    pub day: u8,  // 1 to 31
+    pub hour: u8,  // 0 to 23
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    // seconds since 1970-01-01 00:00:00, RTC is assumed to be in UTC
+    pub fn unix_timestamp(self) -> u64 {
+        let days = days_from_civil(i64::from(self.year), i64::from(self.month), i64::from(self.day));
+        let seconds = i64::from(self.hour) * 3600 + i64::from(self.minute) * 60 + i64::from(self.second);
+        (days * 86400 + seconds).max(0) as u64
+    }
+
+    pub fn from_unix_timestamp(timestamp: u64) -> Self {
+        let days = (timestamp / 86400) as i64;
+        let seconds = timestamp % 86400;
+        let (year, month, day) = civil_from_days(days);
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+               self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+// registers as read from CMOS, before decoding
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: Option<u8>,
+}
+
+// remember Unix time at boot, so that `timestamp` only needs uptime
+// afterwards; call after `time::init`
+pub fn init() {
+    let now = now().unix_timestamp();
+    BOOT_TIME.store(now.saturating_sub(super::uptime().as_secs()), Ordering::SeqCst);
+}
+
+// current date and time read from RTC
+pub fn now() -> DateTime {
+    let century = acpi::fadt().and_then(|fadt| fadt.century());
+    let (raw, status_b) = cpu_interrupts::without_interrupts(|| {
+        let _cmos = CMOS.lock();
+        // values are read until two reads match, as an update may start
+        // right after update in progress flag is checked
+        let mut raw = read_raw(century);
+        loop {
+            let again = read_raw(century);
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+        (raw, read_register(STATUS_B))
+    });
+    decode(raw, status_b)
+}
+
+// wall clock time as duration since Unix epoch, with uptime's
+// resolution; zero based if `init` wasn't called
+pub fn timestamp() -> Duration {
+    Duration::from_secs(BOOT_TIME.load(Ordering::SeqCst)) + super::uptime()
+}
+
+// enable periodic interrupt with frequency `32768 >> (rate - 1)` Hz,
+// rate is between 3 (8192 Hz) and 15 (2 Hz); it's an alternative tick
+// source to PIT, counted by `periodic_ticks`
+pub fn enable_periodic(rate: u8) -> Result<HandlerId, ClaimError> {
+    let handler_id = interrupts::claim_irq(RTC_IRQ, periodic_tick)?;
+    let rate = rate.max(3).min(15);
+    cpu_interrupts::without_interrupts(|| {
+        let _cmos = CMOS.lock();
+        let status_a = read_register(STATUS_A);
+        write_register(STATUS_A, (status_a & 0xf0) | rate);
+        let status_b = read_register(STATUS_B);
+        write_register(STATUS_B, status_b | PERIODIC_INTERRUPT);
+        read_register(STATUS_C);  // clear pending interrupt, otherwise none is raised
+    });
+    Ok(handler_id)
+}
+
+pub fn disable_periodic(handler_id: HandlerId) {
+    cpu_interrupts::without_interrupts(|| {
+        let _cmos = CMOS.lock();
+        let status_b = read_register(STATUS_B);
+        write_register(STATUS_B, status_b & !PERIODIC_INTERRUPT);
+    });
+    interrupts::release(handler_id);
+}
+
+pub fn periodic_ticks() -> u64 {
+    PERIODIC_TICKS.load(Ordering::SeqCst)
+}
+
+// RTC raises no more interrupts until status C is read
+fn periodic_tick() {
+    PERIODIC_TICKS.fetch_add(1, Ordering::SeqCst);
+    let _cmos = CMOS.lock();  // handlers run with interrupts disabled, so it's never held here
+    read_register(STATUS_C);
+}
+
+fn read_raw(century: Option<u8>) -> RawTime {
+    while read_register(STATUS_A) & UPDATE_IN_PROGRESS != 0 {}
+    RawTime {
+        second: read_register(SECONDS),
+        minute: read_register(MINUTES),
+        hour: read_register(HOURS),
+        day: read_register(DAY),
+        month: read_register(MONTH),
+        year: read_register(YEAR),
+        century: century.map(read_register),
+    }
+}
+
+// registers are BCD unless binary flag is set, in 12 hour mode PM is
+// flagged in hours register and midnight is 12; without century
+// register, years are assumed to be in 2000s
+fn decode(raw: RawTime, status_b: u8) -> DateTime {
+    let value = |byte: u8| if status_b & BINARY != 0 { byte } else { from_bcd(byte) };
+
+    let mut hour = value(raw.hour & !PM);
+    if status_b & HOUR_24 == 0 {
+        hour %= 12;
+        if raw.hour & PM != 0 {
+            hour += 12;
+        }
+    }
+    let century = raw.century.map_or(20, value);
+
+    DateTime {
+        year: u16::from(century) * 100 + u16::from(value(raw.year)),
+        month: value(raw.month),
+        day: value(raw.day),
+        hour,
+        minute: value(raw.minute),
+        second: value(raw.second),
+    }
+}
+
+fn from_bcd(byte: u8) -> u8 {
+    (byte >> 4) * 10 + (byte & 0x0f)
+}
+
+// caller must hold `CMOS` lock with interrupts disabled
+fn read_register(register: u8) -> u8 {
+    unsafe {
+        Port::<u8>::new(ADDRESS).write(register);
+        Port::<u8>::new(DATA).read()
+    }
+}
+
+fn write_register(register: u8, value: u8) {
+    unsafe {
+        Port::<u8>::new(ADDRESS).write(register);
+        Port::<u8>::new(DATA).write(value);
+    }
+}
+
+// days since 1970-01-01 of a proleptic Gregorian date, from Howard
+// Hinnant's date algorithms
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = (if year >= 0 { year } else { year - 399 }) / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = (if days >= 0 { days } else { days - 146_096 }) / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+
+#[cfg(all(test, not(target_os = "none")))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decodes_bcd_12_hour() {
+        let raw = RawTime { second: 0x59, minute: 0x30, hour: PM | 0x12, day: 0x31, month: 0x12, year: 0x19,
+                            century: None };
+        let expected = DateTime { year: 2019, month: 12, day: 31, hour: 12, minute: 30, second: 59 };
+        assert_eq!(decode(raw, 0), expected);
+
+        let midnight = RawTime { hour: 0x12, ..raw };
+        assert_eq!(decode(midnight, 0).hour, 0);
+    }
+
+    #[test]
+    fn decodes_binary_24_hour() {
+        let raw = RawTime { second: 5, minute: 4, hour: 23, day: 2, month: 1, year: 99, century: Some(19) };
+        let expected = DateTime { year: 1999, month: 1, day: 2, hour: 23, minute: 4, second: 5 };
+        assert_eq!(decode(raw, BINARY | HOUR_24), expected);
+    }
+
+    #[test]
+    fn converts_unix_timestamp() {
+        let epoch = DateTime { year: 1970, month: 1, day: 1, hour: 0, minute: 0, second: 0 };
+        assert_eq!(epoch.unix_timestamp(), 0);
+
+        let leap_day = DateTime { year: 2020, month: 2, day: 29, hour: 13, minute: 14, second: 15 };
+        assert_eq!(leap_day.unix_timestamp(), 1_582_982_055);
+        assert_eq!(DateTime::from_unix_timestamp(1_582_982_055), leap_day);
+        assert_eq!(leap_day.to_string(), "2020-02-29 13:14:15");
+    }
+}
+
+
+#[cfg(all(test, target_os = "none"))]
+mod kernel_test {
+    use super::*;
+    use crate::time;
+
+    #[test_case]
+    fn reads_plausible_date() {
+        let now = now();
+        assert!(now.year >= 2019 && now.month >= 1 && now.month <= 12, "read {}", now);
+        assert_eq!(DateTime::from_unix_timestamp(now.unix_timestamp()), now);
+    }
+
+    #[test_case]
+    fn periodic_interrupt_ticks() {
+        let handler_id = enable_periodic(6).unwrap();  // 1024 Hz
+        let start = periodic_ticks();
+        time::sleep_ticks(50);
+        disable_periodic(handler_id);
+        assert!(periodic_ticks() > start + 10);
+    }
+}
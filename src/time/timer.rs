@@ -0,0 +1,361 @@
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use spin::Mutex;
+use x86_64::instructions::interrupts as cpu_interrupts;
+
+use super::{ticks, ticks_for};
+use crate::enable_interrupts_and_hlt;
+
+// timers further away than this many ticks stay in their slot for more
+// than one turn of the wheel
+const SLOTS: usize = 256;
+
+type Callback = Box<dyn FnMut() + Send>;
+
+// created by `init`, so that timer interrupt never allocates; users
+// lock it with interrupts disabled, so tick handler always finds it free
+static WHEEL: Mutex<Option<Wheel>> = Mutex::new(None);
+
+// returned when adding a timer, needed to cancel it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerHandle {
+    id: u64,
+}
+
+impl TimerHandle {
+    // stop timer from firing again, returns false if it's a one-shot
+    // timer which already fired or was cancelled before
+    pub fn cancel(self) -> bool {
+        with_wheel(|wheel| wheel.cancel(self.id))
+    }
+
+    // whether timer is going to fire again
+    pub fn is_pending(self) -> bool {
+        with_wheel(|wheel| wheel.is_pending(self.id))
+    }
+}
+
+struct Entry {
+    id: u64,
+    expires: u64,  // tick
+    period: Option<u64>,  // ticks, for periodic timers
+    callback: Callback,
+}
+
+// hashed timing wheel, timer expiring at tick `t` waits in slot
+// `t % SLOTS`; expired timers are moved to ready queue by tick handler
+// and run later by `run_expired` outside interrupt context
+struct Wheel {
+    slots: Vec<Vec<Entry>>,
+    ready: VecDeque<Entry>,  // always has capacity for all timers in slots
+    current: u64,  // last tick slots were checked for
+    timers: usize,  // number of timers in slots
+    next_id: u64,
+    running: Option<u64>,  // periodic timer whose callback is running
+    cancel_running: bool,
+}
+
+impl Wheel {
+    fn new(now: u64) -> Self {
+        let mut slots = Vec::with_capacity(SLOTS);
+        slots.resize_with(SLOTS, Vec::new);
+        Wheel {
+            slots,
+            ready: VecDeque::new(),
+            current: now,
+            timers: 0,
+            next_id: 0,
+            running: None,
+            cancel_running: false,
+        }
+    }
+
+    fn add(&mut self, delay: u64, period: Option<u64>, callback: Callback) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        let expires = self.current + delay;
+        self.insert(Entry { id, expires, period, callback });
+        id
+    }
+
+    // timers already due expire on next tick
+    fn insert(&mut self, mut entry: Entry) {
+        entry.expires = entry.expires.max(self.current + 1);
+        self.slots[entry.expires as usize % SLOTS].push(entry);
+        self.timers += 1;
+        self.ready.reserve(self.timers);  // so that `advance` doesn't allocate
+    }
+
+    // move timers expired until `now` to ready queue, checks each tick
+    // in between in case some ticks were missed
+    fn advance(&mut self, now: u64) {
+        while self.current < now {
+            self.current += 1;
+            let slot = &mut self.slots[self.current as usize % SLOTS];
+            let mut i = 0;
+            while i < slot.len() {
+                if slot[i].expires <= self.current {
+                    self.ready.push_back(slot.swap_remove(i));
+                    self.timers -= 1;
+                } else {
+                    i += 1;
+                }
+            }
+        }
+    }
+
+    // periodic timer is taken out until its callback is done, see `rearm`
+    fn pop_ready(&mut self) -> Option<Entry> {
+        let entry = self.ready.pop_front()?;
+        if entry.period.is_some() {
+            self.running = Some(entry.id);
+            self.cancel_running = false;
+        }
+        Some(entry)
+    }
+
+    // next expiry is counted from previous one so that periodic timers
+    // don't drift, unless they fell behind more than a period
+    fn rearm(&mut self, mut entry: Entry) {
+        self.running = None;
+        if self.cancel_running {
+            return;
+        }
+        if let Some(period) = entry.period {
+            entry.expires += period;
+            self.insert(entry);
+        }
+    }
+
+    fn cancel(&mut self, id: u64) -> bool {
+        if self.running == Some(id) {
+            self.cancel_running = true;
+            return true;
+        }
+        for slot in self.slots.iter_mut() {
+            if let Some(i) = slot.iter().position(|entry| entry.id == id) {
+                slot.swap_remove(i);
+                self.timers -= 1;
+                return true;
+            }
+        }
+        if let Some(i) = self.ready.iter().position(|entry| entry.id == id) {
+            self.ready.remove(i);
+            return true;
+        }
+        false
+    }
+
+    fn is_pending(&self, id: u64) -> bool {
+        (self.running == Some(id) && !self.cancel_running)
+            || self.ready.iter().any(|entry| entry.id == id)
+            || self.slots.iter().flatten().any(|entry| entry.id == id)
+    }
+}
+
+// called by `time::init`, needs heap
+pub(super) fn init() {
+    cpu_interrupts::without_interrupts(|| {
+        let mut wheel = WHEEL.lock();
+        if wheel.is_none() {
+            *wheel = Some(Wheel::new(ticks()));
+        }
+    });
+}
+
+// called on every timer tick, only moves expired timers to ready queue
+pub(super) fn tick(now: u64) {
+    if let Some(mut wheel) = WHEEL.try_lock() {
+        if let Some(wheel) = wheel.as_mut() {
+            wheel.advance(now);
+        }
+    }
+}
+
+// run `callback` once after `delay`, rounded up to whole ticks
+pub fn after<F>(delay: Duration, callback: F) -> TimerHandle
+    where F: FnOnce() + Send + 'static
+{
+    let mut callback = Some(callback);
+    let delay = ticks_for(delay);
+    let id = with_wheel(|wheel| wheel.add(delay, None, Box::new(move || {
+        if let Some(callback) = callback.take() {
+            callback();
+        }
+    })));
+    TimerHandle { id }
+}
+
+// run `callback` every `period` until cancelled, first time after one
+// period
+pub fn every<F>(period: Duration, callback: F) -> TimerHandle
+    where F: FnMut() + Send + 'static
+{
+    let period = ticks_for(period).max(1);
+    let id = with_wheel(|wheel| wheel.add(period, Some(period), Box::new(callback)));
+    TimerHandle { id }
+}
+
+// run callbacks of expired timers, returns how many were run; must not
+// be called from interrupt handlers as callbacks may lock anything
+pub fn run_expired() -> usize {
+    let mut count = 0;
+    while let Some(mut entry) = with_wheel(|wheel| wheel.pop_ready()) {
+        (entry.callback)();
+        count += 1;
+        if entry.period.is_some() {
+            with_wheel(|wheel| wheel.rearm(entry));
+        }
+    }
+    count
+}
+
+// whether some timers expired and `run_expired` has work to do
+pub fn has_expired() -> bool {
+    cpu_interrupts::without_interrupts(|| {
+        WHEEL.lock().as_ref().map_or(false, |wheel| !wheel.ready.is_empty())
+    })
+}
+
+// run timers forever, halting in between
+pub fn run_loop() -> ! {
+    loop {
+        run_expired();
+        cpu_interrupts::disable();
+        if has_expired() {
+            cpu_interrupts::enable();
+        } else {
+            enable_interrupts_and_hlt();
+        }
+    }
+}
+
+fn with_wheel<F, R>(f: F) -> R
+    where F: FnOnce(&mut Wheel) -> R
+{
+    cpu_interrupts::without_interrupts(|| {
+        f(WHEEL.lock().as_mut().expect("timers are not initialized, see `time::init`"))
+    })
+}
+
+
+#[cfg(all(test, not(target_os = "none")))]
+mod test {
+    use super::*;
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    fn counter() -> (Arc<AtomicUsize>, Callback) {
+        let count = Arc::new(AtomicUsize::new(0));
+        let cloned = count.clone();
+        (count, Box::new(move || { cloned.fetch_add(1, Ordering::SeqCst); }))
+    }
+
+    // what `run_expired` does, without locking
+    fn run(wheel: &mut Wheel) -> usize {
+        let mut count = 0;
+        while let Some(mut entry) = wheel.pop_ready() {
+            (entry.callback)();
+            count += 1;
+            wheel.rearm(entry);
+        }
+        count
+    }
+
+    #[test]
+    fn one_shot_fires_once() {
+        let mut wheel = Wheel::new(100);
+        let (count, callback) = counter();
+        let id = wheel.add(5, None, callback);
+        wheel.advance(104);
+        assert_eq!(run(&mut wheel), 0);
+        assert!(wheel.is_pending(id));
+        wheel.advance(110);
+        assert_eq!(run(&mut wheel), 1);
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        assert!(!wheel.is_pending(id));
+        assert!(!wheel.cancel(id));
+    }
+
+    #[test]
+    fn timer_beyond_one_turn_waits() {
+        let mut wheel = Wheel::new(0);
+        let (count, callback) = counter();
+        wheel.add(SLOTS as u64 + 10, None, callback);
+        wheel.advance(10);
+        run(&mut wheel);
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+        wheel.advance(SLOTS as u64 + 10);
+        run(&mut wheel);
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn periodic_fires_until_cancelled() {
+        let mut wheel = Wheel::new(0);
+        let (count, callback) = counter();
+        let id = wheel.add(10, Some(10), callback);
+        for now in 1..=35 {
+            wheel.advance(now);
+            run(&mut wheel);
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+        assert!(wheel.cancel(id));
+        wheel.advance(100);
+        assert_eq!(run(&mut wheel), 0);
+        assert_eq!(wheel.timers, 0);
+    }
+
+    #[test]
+    fn cancels_expired_timer_before_it_runs() {
+        let mut wheel = Wheel::new(0);
+        let (count, callback) = counter();
+        let id = wheel.add(1, None, callback);
+        wheel.advance(1);
+        assert!(wheel.cancel(id));
+        assert_eq!(run(&mut wheel), 0);
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+    }
+}
+
+
+#[cfg(all(test, target_os = "none"))]
+mod kernel_test {
+    use super::*;
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use crate::time;
+
+    #[test_case]
+    fn one_shot_runs_after_delay() {
+        let fired = Arc::new(AtomicUsize::new(0));
+        let cloned = fired.clone();
+        let start = ticks();
+        after(Duration::from_millis(20), move || cloned.store(ticks() as usize, Ordering::SeqCst));
+        while fired.load(Ordering::SeqCst) == 0 {
+            run_expired();
+            x86_64::instructions::hlt();
+        }
+        assert!(fired.load(Ordering::SeqCst) as u64 >= start + 20);
+    }
+
+    #[test_case]
+    fn cancelled_periodic_stops() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let cloned = count.clone();
+        let handle = every(Duration::from_millis(5), move || { cloned.fetch_add(1, Ordering::SeqCst); });
+        while count.load(Ordering::SeqCst) < 3 {
+            run_expired();
+            x86_64::instructions::hlt();
+        }
+        assert!(handle.cancel());
+        assert!(!handle.is_pending());
+        let stopped_at = count.load(Ordering::SeqCst);
+        time::sleep_ticks(20);
+        run_expired();
+        assert_eq!(count.load(Ordering::SeqCst), stopped_at);
+    }
+}
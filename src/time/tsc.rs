@@ -0,0 +1,75 @@
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::sync::atomic::{self, Ordering};
+
+use super::{ticks, DIVISOR, pit};
+
+const CALIBRATION_TICKS: u64 = 50;
+const INVARIANT_TSC: u32 = 1 << 8;  // in edx of cpuid 0x8000_0007
+
+// time stamp counter, calibrated against PIT as its frequency can't
+// be read reliably from CPU
+#[derive(Debug)]
+pub struct Tsc {
+    frequency: u64,  // Hz
+    invariant: bool,
+}
+
+impl Tsc {
+    // measure frequency over a few PIT ticks, interrupts must be enabled
+    pub fn calibrate() -> Tsc {
+        let start = ticks();
+        while ticks() == start {  // start at tick boundary
+            atomic::spin_loop_hint();
+        }
+        let tsc_start = read();
+        let end = start + 1 + CALIBRATION_TICKS;
+        while ticks() < end {
+            atomic::spin_loop_hint();
+        }
+        let cycles = read() - tsc_start;
+
+        let divisor = u64::from(DIVISOR.load(Ordering::SeqCst));
+        let frequency = u128::from(cycles) * u128::from(pit::BASE_FREQUENCY) / u128::from(CALIBRATION_TICKS * divisor);
+        Tsc { frequency: frequency as u64, invariant: is_invariant() }
+    }
+
+    pub fn frequency(&self) -> u64 {
+        self.frequency
+    }
+
+    // invariant TSC runs at constant rate regardless of power states,
+    // otherwise it's not usable for timekeeping on real hardware
+    pub fn is_invariant(&self) -> bool {
+        self.invariant
+    }
+
+    pub fn cycles_to_nanos(&self, cycles: u64) -> u64 {
+        cycles_to_nanos(cycles, self.frequency)
+    }
+}
+
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+fn is_invariant() -> bool {
+    let max_extended = unsafe { __cpuid(0x8000_0000) }.eax;
+    max_extended >= 0x8000_0007 && unsafe { __cpuid(0x8000_0007) }.edx & INVARIANT_TSC != 0
+}
+
+fn cycles_to_nanos(cycles: u64, frequency: u64) -> u64 {
+    (u128::from(cycles) * 1_000_000_000 / u128::from(frequency.max(1))) as u64
+}
+
+
+#[cfg(all(test, not(target_os = "none")))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn converts_cycles_to_nanos() {
+        assert_eq!(cycles_to_nanos(3_000, 3_000_000_000), 1000);
+        assert_eq!(cycles_to_nanos(u64::max_value(), 1_000_000_000), u64::max_value());
+        assert_eq!(cycles_to_nanos(5, 0), 5_000_000_000);  // uncalibrated doesn't divide by zero
+    }
+}
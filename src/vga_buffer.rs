@@ -151,7 +151,7 @@ pub fn _print(args: fmt::Arguments) {
 }
 
 
-#[cfg(test)]
+#[cfg(all(test, not(target_os = "none")))]
 mod test {
     use super::*;
     use array_init::array_init;
@@ -229,3 +229,37 @@ mod test {
         }
     }
 }
+
+
+#[cfg(all(test, target_os = "none"))]
+mod kernel_test {
+    use super::*;
+
+    #[test_case]
+    fn println_many() {
+        for _ in 0..200 {
+            println!("scrolled line");
+        }
+    }
+
+    // line is read with writer locked and interrupts disabled, so that
+    // nothing prints in between, and checked after unlocking, so that a
+    // failure doesn't leave writer locked for later tests
+    #[test_case]
+    fn println_output() {
+        use core::fmt::Write;
+
+        let s = "fits on a single line";
+        let mut line = [0u8; BUFFER_WIDTH];
+        interrupts::without_interrupts(|| {
+            let mut writer = WRITER.lock();
+            writeln!(writer, "{}", s).unwrap();
+            for (i, byte) in line.iter_mut().enumerate() {
+                *byte = writer.buffer.chars[BUFFER_HEIGHT - 2][i].read().ascii_character;
+            }
+        });
+        for (i, c) in s.chars().enumerate() {
+            assert_eq!(char::from(line[i]), c);
+        }
+    }
+}